@@ -0,0 +1,39 @@
+use crate::config::AuditLogAction;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use log::{info, error};
+use uuid::Uuid;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditLogEntry {
+    pub time: DateTime<Utc>,
+    /// The affected installation, none for actions on the daemon itself
+    pub installation: Option<Uuid>,
+    pub action: AuditLogAction,
+}
+
+/// Appends an entry to audit-log.jsonl, one json object per line
+pub fn append(installation: Option<Uuid>, action: AuditLogAction) {
+    let entry = AuditLogEntry {
+        time: Utc::now(),
+        installation,
+        action,
+    };
+    let mut line = serde_json::to_string(&entry).expect("Failed to serialize audit log entry");
+    info!("Audit: {}", line.as_str());
+    line.push('\n');
+    let mut path = env::current_dir().unwrap();
+    path.push("audit-log.jsonl");
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(err) = written {
+        error!("Cannot write audit log {}: {}", path.display(), err);
+    }
+}
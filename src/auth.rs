@@ -0,0 +1,260 @@
+use crate::config::DaemonConfig;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use log::{debug, info, warn, error};
+use thiserror::Error;
+use uuid::Uuid;
+use warp::Filter;
+
+const LOCAL_TOKEN_FILE: &str = "local-token";
+const PAIRED_CLIENTS_FILE: &str = "paired-clients.json";
+const PAIRING_TIMEOUT_MINUTES: i64 = 5;
+const MAX_PENDING_PAIRINGS: usize = 16;
+/// Pairing requests a single source may start within one pairing timeout
+const MAX_PAIRINGS_PER_SOURCE: usize = 3;
+/// Bucket of requests without a known peer address
+const UNKNOWN_SOURCE: &str = "unknown";
+
+/// Rejection for REST calls without a valid token
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PairedClient {
+    pub name: String,
+    pub token: String,
+    pub paired: DateTime<Utc>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PairingRequest {
+    pub client_name: String,
+}
+
+#[derive(Error, Debug)]
+pub enum PairingError {
+    #[error("Too many pairing requests, try again later")]
+    TooManyRequests,
+    #[error("Too many pending pairings, try again later")]
+    TooManyPending,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PairingStarted {
+    pub pairing_id: Uuid,
+    /// Only shown by the client, the user types it in locally with `--confirm-pairing <code>`
+    pub code: String,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "status", content = "data")]
+pub enum PairingStatus {
+    Pending,
+    Confirmed(String),
+}
+
+struct PendingPairing {
+    client_name: String,
+    code: String,
+    expires: DateTime<Utc>,
+    token: Option<String>,
+}
+
+/// Tokens accepted by the REST and WebSocket API: the local token of this machine,
+/// the rest tokens of all installations and the tokens of paired clients
+#[derive(Clone)]
+pub struct Auth {
+    local_token: String,
+    paired: Arc<Mutex<Vec<PairedClient>>>,
+    pending: Arc<Mutex<HashMap<Uuid, PendingPairing>>>,
+    /// Start times of recent pairing requests by source address
+    attempts: Arc<Mutex<HashMap<String, Vec<DateTime<Utc>>>>>,
+}
+
+fn file_path(name: &str) -> PathBuf {
+    let mut path = env::current_dir().unwrap();
+    path.push(name);
+    path
+}
+
+fn generate_token() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
+/// Reads the token of this machine, which the CLI and one-click use to talk to the daemon
+pub fn local_token() -> Option<String> {
+    std::fs::read_to_string(file_path(LOCAL_TOKEN_FILE)).ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+fn read_or_create_local_token() -> String {
+    if let Some(token) = local_token() {
+        return token;
+    }
+    let token = generate_token();
+    if let Err(err) = std::fs::write(file_path(LOCAL_TOKEN_FILE), token.as_bytes()) {
+        error!("Cannot write local token: {}", err);
+    }
+    token
+}
+
+fn read_paired_clients() -> Vec<PairedClient> {
+    match std::fs::read(file_path(PAIRED_CLIENTS_FILE)) {
+        Ok(data) => serde_json::from_slice(data.as_ref()).unwrap_or_else(|err| {
+            warn!("Invalid paired clients file: {}", err);
+            Vec::new()
+        }),
+        Err(_) => Vec::new()
+    }
+}
+
+fn write_paired_clients(clients: &[PairedClient]) {
+    let value = serde_json::to_vec(clients).expect("Failed to serialize paired clients");
+    if let Err(err) = std::fs::write(file_path(PAIRED_CLIENTS_FILE), value) {
+        error!("Cannot write paired clients: {}", err);
+    }
+}
+
+impl Auth {
+    pub fn new() -> Auth {
+        Auth {
+            local_token: read_or_create_local_token(),
+            paired: Arc::new(Mutex::new(read_paired_clients())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            attempts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn is_valid(&self, config: &DaemonConfig, token: &str) -> bool {
+        if token.is_empty() {
+            return false;
+        }
+        if token.eq(self.local_token.as_str()) {
+            return true;
+        }
+        if self.paired.lock().await.iter().any(|client| client.token.eq(token)) {
+            return true;
+        }
+        config.get_configs().await.iter()
+            .any(|config| !config.rest_token.is_empty() && config.rest_token.eq(token))
+    }
+
+    pub async fn start_pairing(&self, request: PairingRequest, source: Option<SocketAddr>) -> Result<PairingStarted, PairingError> {
+        let now = Utc::now();
+        let window_start = now - Duration::minutes(PAIRING_TIMEOUT_MINUTES);
+        let source = source.map(|source| source.ip().to_string()).unwrap_or_else(|| UNKNOWN_SOURCE.to_string());
+        let mut attempts = self.attempts.lock().await;
+        attempts.retain(|_, started| {
+            started.retain(|started| *started > window_start);
+            !started.is_empty()
+        });
+        let started = attempts.entry(source.clone()).or_default();
+        if started.len() >= MAX_PAIRINGS_PER_SOURCE {
+            warn!("Rejected pairing request of \"{}\" from {}, too many requests", request.client_name, source);
+            return Err(PairingError::TooManyRequests);
+        }
+        let mut pending = self.pending.lock().await;
+        pending.retain(|_, pairing| pairing.expires > now);
+        if pending.len() >= MAX_PENDING_PAIRINGS {
+            warn!("Rejected pairing request of \"{}\" from {}, too many pending pairings", request.client_name, source);
+            return Err(PairingError::TooManyPending);
+        }
+        started.push(now);
+        let pairing_id = Uuid::new_v4();
+        let code = format!("{:06}", pairing_id.as_u128() % 1_000_000);
+        let expires = now + Duration::minutes(PAIRING_TIMEOUT_MINUTES);
+        warn!("Pairing requested by \"{}\" from {}. Confirm it with --confirm-pairing and the code shown by the client", request.client_name, source);
+        pending.insert(pairing_id, PendingPairing {
+            client_name: request.client_name,
+            code: code.clone(),
+            expires,
+            token: None,
+        });
+        Ok(PairingStarted {
+            pairing_id,
+            code,
+            expires,
+        })
+    }
+
+    /// Returns the pairing status, a confirmed token is only handed out once
+    pub async fn pairing_status(&self, pairing_id: &Uuid) -> Option<PairingStatus> {
+        let mut pending = self.pending.lock().await;
+        let pairing = pending.get(pairing_id)?;
+        if pairing.expires <= Utc::now() {
+            pending.remove(pairing_id);
+            return None;
+        }
+        match pairing.token.clone() {
+            Some(token) => {
+                pending.remove(pairing_id);
+                Some(PairingStatus::Confirmed(token))
+            }
+            None => Some(PairingStatus::Pending)
+        }
+    }
+
+    pub async fn confirm_pairing(&self, code: &str) -> Option<String> {
+        let mut pending = self.pending.lock().await;
+        let now = Utc::now();
+        let pairing = pending.values_mut()
+            .find(|pairing| pairing.code.eq(code) && pairing.token.is_none() && pairing.expires > now)?;
+        let token = generate_token();
+        pairing.token = Some(token.clone());
+        let client_name = pairing.client_name.clone();
+        drop(pending);
+        let mut paired = self.paired.lock().await;
+        paired.push(PairedClient {
+            name: client_name.clone(),
+            token,
+            paired: now,
+        });
+        write_paired_clients(paired.as_slice());
+        info!("Paired client \"{}\"", client_name);
+        Some(client_name)
+    }
+}
+
+/// Reads a bearer token from the Authorization header
+fn token_from_request() -> impl Filter<Extract=(Option<String>, ), Error=warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .map(|header: Option<String>| header
+            .map(|header| header.trim_start_matches("Bearer ").trim().to_string()))
+}
+
+/// Rejects requests without a valid token
+pub fn authenticated(config: DaemonConfig) -> impl Filter<Extract=(), Error=warp::Rejection> + Clone {
+    token_from_request()
+        .and(warp::any().map(move || config.clone()))
+        .and_then(|token: Option<String>, config: DaemonConfig| async move {
+            match token {
+                Some(token) if config.auth.is_valid(&config, token.as_str()).await => Ok(()),
+                _ => {
+                    debug!("Rejected unauthenticated request");
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+pub async fn handle_rejection(rejection: warp::Rejection) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Unauthorized", warp::http::StatusCode::UNAUTHORIZED)))
+    } else {
+        Err(rejection)
+    }
+}
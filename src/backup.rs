@@ -0,0 +1,367 @@
+use crate::config::{DaemonConfig, LocalData, MapData};
+use crate::websocket_handler::{ConfigData, InstallType};
+use crate::beatsaver;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use log::{debug, info, warn};
+use thiserror::Error;
+use uuid::Uuid;
+use zip::write::FileOptions;
+
+const MANIFEST_VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "manifest.json";
+const ARCHIVE_MAPS_FOLDER: &str = "CustomLevels/";
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("IO error at {1}: {0}")]
+    IoError(std::io::Error, PathBuf),
+    #[error("Invalid backup manifest: {0}")]
+    JsonError(serde_json::Error),
+    #[error("Invalid backup archive: {0}")]
+    ZipError(zip::result::ZipError),
+    #[error("Backup archive contains no manifest")]
+    MissingManifest,
+    #[error("Backup task failed: {0}")]
+    JoinError(tokio::task::JoinError),
+}
+
+/// Everything needed to set up an installation again: installed maps, playlists and mods
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupManifest {
+    pub version: u32,
+    pub installation: Uuid,
+    pub install_type: InstallType,
+    pub created: DateTime<Utc>,
+    pub maps: Vec<BackupMap>,
+    pub playlists: Vec<BackupPlaylist>,
+    pub mods: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupMap {
+    pub hash: String,
+    pub key: Option<String>,
+    pub song_name: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupPlaylist {
+    pub file_name: String,
+    pub contents: serde_json::Value,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupResult {
+    pub installation: Uuid,
+    pub path: PathBuf,
+    pub archive: bool,
+    pub maps: usize,
+    pub playlists: usize,
+    pub mods: usize,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreRequest {
+    pub path: PathBuf,
+    /// Extracts the maps of a full backup archive instead of downloading them again
+    #[serde(default)]
+    pub from_archive: bool,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreResult {
+    pub installation: Uuid,
+    pub extracted: usize,
+    pub already_installed: usize,
+    pub queued: Vec<String>,
+    pub unresolvable: Vec<String>,
+    pub playlists: usize,
+    /// Mods can't be installed by the daemon yet, they are only listed
+    pub skipped_mods: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+pub fn backups_folder() -> PathBuf {
+    let mut path = std::env::current_dir().unwrap();
+    path.push("backups");
+    path
+}
+
+fn installed_mods(data: &LocalData) -> Vec<String> {
+    if data.config.install_type != InstallType::PC {
+        return Vec::new();
+    }
+    let mut path = PathBuf::from(data.config.install_location.as_str());
+    path.push("Plugins");
+    let mut mods = match std::fs::read_dir(path) {
+        Ok(dir) => dir.filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map(|extension| extension.eq("dll")).unwrap_or(false))
+            .filter_map(|path| path.file_stem().map(|name| name.to_string_lossy().to_string()))
+            .collect::<Vec<String>>(),
+        Err(_) => Vec::new()
+    };
+    mods.sort();
+    mods
+}
+
+pub async fn create_manifest(data: &LocalData) -> BackupManifest {
+    let maps = data.map_index.lock().await.maps.iter()
+        .filter_map(|map| match map {
+            MapData::Valid(meta) => Some(BackupMap {
+                hash: meta.hash.to_lowercase(),
+                key: Some(format!("{:x}", meta.id)),
+                song_name: meta.info.as_ref().map(|info| info.song_name.clone()),
+            }),
+            MapData::Unknown(unknown) => Some(BackupMap {
+                hash: unknown.hash.to_lowercase(),
+                key: None,
+                song_name: unknown.info.as_ref().map(|info| info.song_name.clone()),
+            }),
+            MapData::Invalid(_) => None
+        })
+        .collect();
+    let playlists = if data.config.install_type == InstallType::PC {
+        crate::playlists::read_playlists(data.config.install_location.as_str()).into_iter()
+            .filter_map(|(path, playlist)| Some(BackupPlaylist {
+                file_name: path.file_name()?.to_string_lossy().to_string(),
+                contents: serde_json::to_value(playlist).ok()?,
+            }))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    BackupManifest {
+        version: MANIFEST_VERSION,
+        installation: data.config.id,
+        install_type: data.config.install_type.clone(),
+        created: Utc::now(),
+        maps,
+        playlists,
+        mods: installed_mods(data),
+    }
+}
+
+fn add_folder_to_archive(archive: &mut zip::ZipWriter<File>, folder: &Path, prefix: &str) -> Result<(), BackupError> {
+    let dir = std::fs::read_dir(folder)
+        .map_err(|err| BackupError::IoError(err, folder.to_path_buf()))?;
+    for entry in dir.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let mut name = prefix.to_string();
+        name.push_str(entry.file_name().to_string_lossy().as_ref());
+        if path.is_dir() {
+            name.push('/');
+            archive.add_directory(name.as_str(), FileOptions::default())
+                .map_err(BackupError::ZipError)?;
+            add_folder_to_archive(archive, path.as_path(), name.as_str())?;
+        } else {
+            archive.start_file(name.as_str(), FileOptions::default())
+                .map_err(BackupError::ZipError)?;
+            let mut file = File::open(&path)
+                .map_err(|err| BackupError::IoError(err, path.clone()))?;
+            std::io::copy(&mut file, archive)
+                .map_err(|err| BackupError::IoError(err, path.clone()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a backup of an installation into the backups folder. The manifest is written as plain json,
+/// or together with the whole CustomLevels folder into a zip archive.
+pub async fn create_backup(data: &LocalData, archive: bool) -> Result<BackupResult, BackupError> {
+    let manifest = create_manifest(data).await;
+    let config = data.config.clone();
+    // archiving a whole library takes a while, so keep it off the runtime's worker threads
+    tokio::task::spawn_blocking(move || write_backup(&config, &manifest, archive)).await
+        .map_err(BackupError::JoinError)?
+}
+
+fn write_backup(config: &ConfigData, manifest: &BackupManifest, archive: bool) -> Result<BackupResult, BackupError> {
+    let folder = backups_folder();
+    std::fs::create_dir_all(&folder)
+        .map_err(|err| BackupError::IoError(err, folder.clone()))?;
+    let mut path = folder;
+    path.push(format!("{}-{}.{}", config.id, manifest.created.format("%Y%m%d-%H%M%S"),
+                      if archive { "zip" } else { "json" }));
+    let contents = serde_json::to_vec_pretty(manifest).map_err(BackupError::JsonError)?;
+    let file = File::create(&path)
+        .map_err(|err| BackupError::IoError(err, path.clone()))?;
+    if archive && config.install_type == InstallType::PC {
+        let maps_folder = crate::song_core::custom_levels_folder(config.install_location.as_str());
+        let mut writer = zip::ZipWriter::new(file);
+        writer.start_file(MANIFEST_FILE, FileOptions::default()).map_err(BackupError::ZipError)?;
+        writer.write_all(contents.as_ref())
+            .map_err(|err| BackupError::IoError(err, path.clone()))?;
+        writer.add_directory(ARCHIVE_MAPS_FOLDER, FileOptions::default()).map_err(BackupError::ZipError)?;
+        add_folder_to_archive(&mut writer, maps_folder.as_path(), ARCHIVE_MAPS_FOLDER)?;
+        writer.finish().map_err(BackupError::ZipError)?;
+    } else {
+        if archive {
+            warn!("Map archives are only supported for PC installations, writing the manifest only");
+        }
+        let mut file = file;
+        file.write_all(contents.as_ref())
+            .map_err(|err| BackupError::IoError(err, path.clone()))?;
+    }
+    info!("Wrote backup of {} to {}", config.id, path.display());
+    Ok(BackupResult {
+        installation: config.id,
+        archive: archive && config.install_type == InstallType::PC,
+        path,
+        maps: manifest.maps.len(),
+        playlists: manifest.playlists.len(),
+        mods: manifest.mods.len(),
+    })
+}
+
+fn is_archive(path: &Path) -> bool {
+    path.extension().map(|extension| extension.eq("zip")).unwrap_or(false)
+}
+
+pub fn read_manifest(path: &Path) -> Result<BackupManifest, BackupError> {
+    let mut file = File::open(path)
+        .map_err(|err| BackupError::IoError(err, path.to_path_buf()))?;
+    if is_archive(path) {
+        let mut archive = zip::ZipArchive::new(file).map_err(BackupError::ZipError)?;
+        let mut contents = Vec::new();
+        match archive.by_name(MANIFEST_FILE) {
+            Ok(mut manifest) => manifest.read_to_end(&mut contents)
+                .map_err(|err| BackupError::IoError(err, path.to_path_buf()))?,
+            Err(zip::result::ZipError::FileNotFound) => return Err(BackupError::MissingManifest),
+            Err(err) => return Err(BackupError::ZipError(err))
+        };
+        serde_json::from_slice(contents.as_ref()).map_err(BackupError::JsonError)
+    } else {
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
+            .map_err(|err| BackupError::IoError(err, path.to_path_buf()))?;
+        serde_json::from_slice(contents.as_ref()).map_err(BackupError::JsonError)
+    }
+}
+
+/// Extracts the maps of a backup archive into the CustomLevels folder, keeping already existing files
+fn extract_archive(path: &Path, target: &Path) -> Result<usize, BackupError> {
+    let file = File::open(path)
+        .map_err(|err| BackupError::IoError(err, path.to_path_buf()))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(BackupError::ZipError)?;
+    let mut extracted = 0;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(BackupError::ZipError)?;
+        let relative = match entry.enclosed_name()
+            .and_then(|name| name.strip_prefix(ARCHIVE_MAPS_FOLDER).ok())
+            .map(|name| name.to_path_buf()) {
+            Some(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => continue
+        };
+        let mut out_path = target.to_path_buf();
+        out_path.push(relative.as_path());
+        if entry.is_dir() {
+            std::fs::create_dir_all(&out_path)
+                .map_err(|err| BackupError::IoError(err, out_path.clone()))?;
+            if relative.components().count() == 1 {
+                extracted += 1;
+            }
+            continue;
+        }
+        if out_path.exists() {
+            debug!("Skipping existing file {}", out_path.display());
+            continue;
+        }
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|err| BackupError::IoError(err, parent.to_path_buf()))?;
+        }
+        let mut out_file = File::create(&out_path)
+            .map_err(|err| BackupError::IoError(err, out_path.clone()))?;
+        std::io::copy(&mut entry, &mut out_file)
+            .map_err(|err| BackupError::IoError(err, out_path.clone()))?;
+    }
+    Ok(extracted)
+}
+
+fn restore_playlists(data: &LocalData, playlists: &[BackupPlaylist], result: &mut RestoreResult) {
+    if data.config.install_type != InstallType::PC || playlists.is_empty() {
+        return;
+    }
+    let folder = crate::playlists::playlists_folder(data.config.install_location.as_str());
+    if let Err(err) = std::fs::create_dir_all(&folder) {
+        result.errors.push(format!("{}: {}", folder.display(), err));
+        return;
+    }
+    for playlist in playlists {
+        let file_name = match Path::new(playlist.file_name.as_str()).file_name() {
+            Some(file_name) => file_name.to_os_string(),
+            None => continue
+        };
+        let mut path = folder.clone();
+        path.push(file_name);
+        if path.exists() {
+            continue;
+        }
+        let written = serde_json::to_vec_pretty(&playlist.contents)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| std::fs::write(&path, contents).map_err(|err| err.to_string()));
+        match written {
+            Ok(_) => result.playlists += 1,
+            Err(err) => result.errors.push(format!("{}: {}", path.display(), err))
+        }
+    }
+}
+
+/// Restores a backup into an installation. Maps are extracted from the archive if requested,
+/// every map which is still missing afterwards gets queued for download.
+pub async fn restore_backup(config: &DaemonConfig, data: &LocalData, request: &RestoreRequest) -> Result<RestoreResult, BackupError> {
+    let manifest = read_manifest(request.path.as_path())?;
+    info!("Restoring backup {} ({} maps) into {}", request.path.display(), manifest.maps.len(), data.config.id);
+    let mut result = RestoreResult {
+        installation: data.config.id,
+        skipped_mods: manifest.mods.clone(),
+        ..RestoreResult::default()
+    };
+
+    if request.from_archive && is_archive(request.path.as_path()) {
+        if data.config.install_type == InstallType::PC {
+            let target = crate::song_core::custom_levels_folder(data.config.install_location.as_str());
+            let path = request.path.clone();
+            result.extracted = tokio::task::spawn_blocking(move || extract_archive(path.as_path(), target.as_path())).await
+                .map_err(BackupError::JoinError)??;
+            data.clone().update_map_index(false).await;
+        } else {
+            result.errors.push("Map archives can only be restored on PC installations".to_string());
+        }
+    }
+
+    restore_playlists(data, manifest.playlists.as_slice(), &mut result);
+
+    for map in manifest.maps {
+        if data.is_map_installed(map.hash.as_str()).await {
+            result.already_installed += 1;
+            continue;
+        }
+        let key = match map.key {
+            Some(key) => Some(key),
+            None => beatsaver::resolve_map_by_hash(map.hash.as_str()).await.ok()
+                .map(|resolved| resolved.id)
+        };
+        match key {
+            Some(key) => match config.queue_map_for(key.clone(), vec![data.config.id]).await {
+                Ok(_) => result.queued.push(key),
+                Err(err) => result.errors.push(format!("{}: {}", key, err))
+            },
+            None => result.unresolvable.push(map.hash)
+        }
+    }
+    info!("Restore queued {} maps, {} already installed, {} unresolvable",
+          result.queued.len(), result.already_installed, result.unresolvable.len());
+    Ok(result)
+}
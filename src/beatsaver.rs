@@ -1,8 +1,22 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
-use log::info;
+use log::{debug, info, warn};
 use thiserror::Error;
+use std::path::{Path, PathBuf};
+use std::env;
+use tokio::io::AsyncWriteExt;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+use crate::map_index::IndexError;
+use std::collections::HashMap;
+use std::sync::Arc;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Locks of the partial downloads, only one request at a time may write a `.part` file
+    static ref PART_LOCKS: std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>> = std::sync::Mutex::new(HashMap::new());
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -14,6 +28,7 @@ pub struct BeatSaverMap {
     pub automapper: bool,
     pub ranked: bool,
     pub qualified: bool,
+    pub uploaded: Option<DateTime<Utc>>,
     pub versions: Vec<MapVersion>,
 }
 
@@ -54,14 +69,124 @@ pub enum BeatSaverDownloadError {
     BeatSaverError(#[from] BeatSaverError),
     #[error("Map {0} seems to have no versions")]
     NoMapVersion(String),
+    #[error("An error occurred when writing download to {1}: {0}")]
+    IoError(std::io::Error, PathBuf),
+    #[error("Download {0} is incomplete: got {1} of {2} bytes")]
+    IncompleteDownload(PathBuf, u64, u64),
+    #[error("Downloaded archive is not a valid map: {0}")]
+    InvalidArchive(IndexError),
+    #[error("Downloaded map hash {1} does not match the expected hash {0}")]
+    HashMismatch(String, String),
+}
+
+impl BeatSaverError {
+    /// Label of the error in the metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BeatSaverError::RequestError(_, _) => "request",
+            BeatSaverError::StatusCodeError(_) => "status",
+            BeatSaverError::JsonError(_, _, _) => "json",
+        }
+    }
+}
+
+impl BeatSaverDownloadError {
+    /// Label of the error in the metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BeatSaverDownloadError::BeatSaverError(error) => error.kind(),
+            BeatSaverDownloadError::NoMapVersion(_) => "no_version",
+            BeatSaverDownloadError::IoError(_, _) => "io",
+            BeatSaverDownloadError::IncompleteDownload(_, _, _) => "incomplete",
+            BeatSaverDownloadError::InvalidArchive(_) => "invalid_archive",
+            BeatSaverDownloadError::HashMismatch(_, _) => "hash_mismatch",
+        }
+    }
+
+    /// Whether a partial download should be kept and resumed after this error
+    fn is_resumable(&self) -> bool {
+        match self {
+            BeatSaverDownloadError::BeatSaverError(BeatSaverError::RequestError(_, _)) => true,
+            BeatSaverDownloadError::BeatSaverError(BeatSaverError::StatusCodeError(code)) => {
+                *code == StatusCode::RANGE_NOT_SATISFIABLE.as_u16() || *code >= 500
+            }
+            BeatSaverDownloadError::IncompleteDownload(_, _, _) => true,
+            _ => false
+        }
+    }
+}
+
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+
+/// A downloaded map archive on disk. The file is removed once the last owner drops it.
+/// Every download gets its own file, so concurrent requests for the same map don't share it.
+pub struct MapDownload {
+    path: PathBuf,
+    hash: String,
 }
 
-pub(crate) async fn retrieve_map_data(map: &BeatSaverMap) -> Result<(MapVersion, Vec<u8>), BeatSaverDownloadError> {
+impl MapDownload {
+    fn create(hash: &str) -> std::io::Result<MapDownload> {
+        let mut path = env::current_dir()?;
+        path.push("downloads");
+        std::fs::create_dir_all(&path)?;
+        let mut file_name = hash.to_owned();
+        file_name.push('-');
+        file_name.push_str(uuid::Uuid::new_v4().to_simple().to_string().as_str());
+        file_name.push_str(".zip");
+        path.push(file_name);
+        Ok(MapDownload {
+            path,
+            hash: hash.to_owned(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Partial downloads are named after the hash only, so a later request for the map resumes them
+    fn part_path(&self) -> PathBuf {
+        let mut file_name = self.hash.clone();
+        file_name.push_str(".zip.part");
+        self.path.with_file_name(file_name)
+    }
+
+    /// The ETag of the partial download, needed to resume it with `If-Range`
+    fn etag_path(&self) -> PathBuf {
+        let mut file_name = self.hash.clone();
+        file_name.push_str(".zip.part.etag");
+        self.path.with_file_name(file_name)
+    }
+
+    /// Waits until no other request writes the partial download of this map
+    async fn lock_part(&self) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = PART_LOCKS.lock().unwrap()
+            .entry(self.hash.to_lowercase())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+}
+
+fn release_part(hash: &str, guard: tokio::sync::OwnedMutexGuard<()>) {
+    drop(guard);
+    // the map holds one reference of each lock, everything else belongs to waiting requests
+    PART_LOCKS.lock().unwrap().retain(|key, lock| key.ne(&hash.to_lowercase()) || Arc::strong_count(lock) > 1);
+}
+
+impl Drop for MapDownload {
+    fn drop(&mut self) {
+        debug!("Removing download {}", self.path.display());
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+pub(crate) async fn retrieve_map_data(map: &BeatSaverMap) -> Result<(MapVersion, MapDownload), BeatSaverDownloadError> {
     if let Some(version) = find_latest_version(map) {
         info!("Downloading map with hash {}", version.hash.as_str());
         download_zip(&version).await
             .map(|data| (version, data))
-            .map_err(|err| err.into())
     } else {
         Err(BeatSaverDownloadError::NoMapVersion(map.id.clone()))
     }
@@ -73,32 +198,162 @@ pub fn find_latest_version(map: &BeatSaverMap) -> Option<MapVersion> {
     versions.pop()
 }
 
-pub async fn download_zip(version: &MapVersion) -> Result<Vec<u8>, BeatSaverError> {
+/// Downloads the archive of a map version into the downloads folder.
+/// Interrupted downloads are kept as `.part` files and resumed via range requests.
+pub async fn download_zip(version: &MapVersion) -> Result<MapDownload, BeatSaverDownloadError> {
+    let started = Instant::now();
+    let result = download_verified_zip(version).await;
+    crate::metrics::record_download(started.elapsed(), result.as_ref().err().map(|error| error.kind()));
+    result
+}
+
+async fn download_verified_zip(version: &MapVersion) -> Result<MapDownload, BeatSaverDownloadError> {
     let client = reqwest::Client::builder()
         .connect_timeout(Duration::from_secs(5))
         .timeout(Duration::from_secs(600))
         .build().unwrap();
-    let download_url = version.download_url.clone();
-    let result = client.get(download_url.clone())
-        .header("User-Agent", "AIOSaber-Client")
-        .send()
-        .await;
-    match result {
-        Ok(response) => {
-            if response.status().is_success() {
-                match response.bytes().await {
-                    Ok(bytes) => {
-                        let buf = bytes.clone();
-                        return Ok(buf.to_vec());
-                    }
-                    Err(error) => Err(BeatSaverError::RequestError(error, download_url))
+    let download = MapDownload::create(version.hash.as_str())
+        .map_err(|error| BeatSaverDownloadError::IoError(error, PathBuf::from("downloads")))?;
+    let guard = download.lock_part().await;
+    let result = download_to_part(&client, version, &download).await;
+    release_part(version.hash.as_str(), guard);
+    result?;
+
+    let path = download.path().to_path_buf();
+    let hash = tokio::task::spawn_blocking(move || crate::map_index::generate_hash_from_zip(path.as_path())).await
+        .map_err(|error| BeatSaverDownloadError::InvalidArchive(IndexError::JoinError(error, download.path().to_path_buf())))?
+        .map_err(BeatSaverDownloadError::InvalidArchive)?;
+    if hash.eq_ignore_ascii_case(version.hash.as_str()) {
+        Ok(download)
+    } else {
+        Err(BeatSaverDownloadError::HashMismatch(version.hash.clone(), hash))
+    }
+}
+
+/// Downloads into the `.part` file of the map and moves it to the download path once it is complete
+async fn download_to_part(client: &reqwest::Client, version: &MapVersion, download: &MapDownload) -> Result<(), BeatSaverDownloadError> {
+    let part_path = download.part_path();
+    let etag_path = download.etag_path();
+    let mut etag = tokio::fs::read_to_string(etag_path.as_path()).await.ok();
+    let mut attempt = 0;
+    loop {
+        match download_part(client, version.download_url.as_str(), part_path.as_path(), etag_path.as_path(), &mut etag).await {
+            Ok(_) => break,
+            Err(error) => {
+                attempt += 1;
+                if !error.is_resumable() {
+                    tokio::fs::remove_file(part_path.as_path()).await.ok();
+                    tokio::fs::remove_file(etag_path.as_path()).await.ok();
+                    return Err(error);
                 }
-            } else {
-                Err(BeatSaverError::StatusCodeError(response.status().as_u16()))
+                if attempt >= DOWNLOAD_ATTEMPTS {
+                    // keep the partial file, the next request for this map resumes it
+                    return Err(error);
+                }
+                let backoff = Duration::from_secs(2u64.pow(attempt));
+                warn!("Download of {} interrupted ({}), resuming in {}s", version.hash.as_str(), error, backoff.as_secs());
+                tokio::time::sleep(backoff).await;
             }
         }
-        Err(error) => Err(BeatSaverError::RequestError(error, download_url))
     }
+    tokio::fs::remove_file(etag_path.as_path()).await.ok();
+    tokio::fs::rename(part_path.as_path(), download.path()).await
+        .map_err(|error| BeatSaverDownloadError::IoError(error, part_path))
+}
+
+async fn download_part(client: &reqwest::Client, download_url: &str, part_path: &Path, etag_path: &Path,
+                       etag: &mut Option<String>) -> Result<(), BeatSaverDownloadError> {
+    let mut offset = tokio::fs::metadata(part_path).await
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    if offset > 0 && etag.is_none() {
+        // without a validator the partial file might belong to an older version of the file
+        debug!("No ETag known for {}, starting over", part_path.display());
+        tokio::fs::remove_file(part_path).await.ok();
+        offset = 0;
+    }
+    let mut request = client.get(download_url)
+        .header("User-Agent", "AIOSaber-Client");
+    if offset > 0 {
+        debug!("Resuming {} at byte {}", part_path.display(), offset);
+        let mut range = "bytes=".to_owned();
+        range.push_str(offset.to_string().as_str());
+        range.push('-');
+        request = request.header(RANGE, range);
+        if let Some(etag) = etag.as_ref() {
+            request = request.header(IF_RANGE, etag.as_str());
+        }
+    }
+    let mut response = request.send().await
+        .map_err(|error| BeatSaverError::RequestError(error, download_url.to_owned()))?;
+    let status = response.status();
+    if status == StatusCode::RANGE_NOT_SATISFIABLE {
+        // the partial file doesn't match the remote file anymore, start over
+        tokio::fs::remove_file(part_path).await.ok();
+    }
+    if !status.is_success() {
+        return Err(BeatSaverError::StatusCodeError(status.as_u16()).into());
+    }
+    let resumed = status == StatusCode::PARTIAL_CONTENT;
+    let received_etag = response.headers().get(ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned());
+    if !resumed || received_etag.is_some() {
+        // a full response replaces the partial file, so its ETag replaces the stored one as well
+        *etag = received_etag;
+        match etag.as_ref() {
+            Some(etag) => tokio::fs::write(etag_path, etag.as_bytes()).await.ok(),
+            None => tokio::fs::remove_file(etag_path).await.ok()
+        };
+    }
+    let expected_length = if resumed {
+        content_range_length(response.headers())
+    } else {
+        response.content_length()
+    };
+    let mut file = if resumed {
+        tokio::fs::OpenOptions::new().append(true).open(part_path).await
+    } else {
+        tokio::fs::File::create(part_path).await
+    }.map_err(|error| BeatSaverDownloadError::IoError(error, part_path.to_path_buf()))?;
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                crate::metrics::record_download_bytes(chunk.len() as u64);
+                file.write_all(chunk.as_ref()).await
+                    .map_err(|error| BeatSaverDownloadError::IoError(error, part_path.to_path_buf()))?;
+            }
+            Ok(None) => break,
+            Err(error) => {
+                file.flush().await.ok();
+                return Err(BeatSaverError::RequestError(error, download_url.to_owned()).into());
+            }
+        }
+    }
+    file.flush().await
+        .map_err(|error| BeatSaverDownloadError::IoError(error, part_path.to_path_buf()))?;
+    drop(file);
+
+    let length = tokio::fs::metadata(part_path).await
+        .map(|metadata| metadata.len())
+        .map_err(|error| BeatSaverDownloadError::IoError(error, part_path.to_path_buf()))?;
+    match expected_length {
+        Some(expected_length) if expected_length != length => {
+            if length > expected_length {
+                tokio::fs::remove_file(part_path).await.ok();
+            }
+            Err(BeatSaverDownloadError::IncompleteDownload(part_path.to_path_buf(), length, expected_length))
+        }
+        _ => Ok(())
+    }
+}
+
+/// Reads the total length from a `Content-Range: bytes <start>-<end>/<total>` header
+fn content_range_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit('/').next())
+        .and_then(|total| total.parse().ok())
 }
 
 pub fn get_beatsaver_base_url() -> String {
@@ -131,9 +386,12 @@ async fn execute_beatsaver_map_request(url: String) -> Result<BeatSaverMap, Beat
         .timeout(Duration::from_secs(5))
         .build()
         .unwrap();
-    match client.get(url.clone())
+    let started = Instant::now();
+    let response = client.get(url.clone())
         .header("User-Agent", "AIOSaber-Client")
-        .send().await {
+        .send().await;
+    crate::metrics::record_beatsaver_request(started.elapsed(), response.as_ref().ok().map(|response| response.status().as_u16()));
+    match response {
         Ok(response) => {
             if response.status().is_success() {
                 match response.text().await {
@@ -1,8 +1,9 @@
 use crate::websocket_handler::{ConfigData, InstallType};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::Mutex;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Write;
 use yaml_rust::{YamlLoader, Yaml, YamlEmitter};
 use log::{debug, info, warn, error};
 use std::str::FromStr;
@@ -11,118 +12,410 @@ use std::env;
 use crate::queue_handler::{DownloadQueueRequest, InstallerQueueRequest, InstallerQueue};
 use serde::{Serialize, Deserialize};
 use std::path::PathBuf;
-use crate::map_index::IndexError;
+use crate::map_index::{IndexError, MapFingerprint, LocalMapInfo};
+use crate::beatsaver::BeatSaverMap;
+use crate::map_query::MapStatus;
+use chrono::{DateTime, Utc};
 use crate::beatsaver::BeatSaverError;
 use uuid::Uuid;
 use std::collections::HashMap;
 use crate::file_watcher::PcMapsWatcher;
+use crate::sync::{MirrorConfig, SyncMode};
+use crate::routing::RoutingRules;
+use crate::quota::QuotaConfig;
+use crate::auth::Auth;
+use crate::daemon_settings::DaemonSettings;
+use lazy_static::lazy_static;
+
+/// Maps an installation can have waiting for its installer queue
+pub const INSTALLER_QUEUE_SIZE: usize = 1024;
+
+lazy_static! {
+    /// Ids of installations whose map index got written
+    static ref INDEX_EVENTS: tokio::sync::broadcast::Sender<Uuid> = tokio::sync::broadcast::channel(256).0;
+    static ref CONFIG_FILE: std::sync::Mutex<ConfigFileState> = std::sync::Mutex::new(ConfigFileState::default());
+}
+
+#[derive(Default)]
+struct ConfigFileState {
+    /// Contents of the last write, so the config watcher can skip its own changes
+    written: Option<String>,
+    /// Problems of daemon-config.yaml on disk, the file isn't overwritten while there are any
+    errors: Vec<String>,
+    /// Daemon settings of the last read, written back in front of the installations
+    settings: Option<DaemonSettings>,
+}
+
+/// Problems of daemon-config.yaml which keep it from being applied
+pub fn config_file_errors() -> Vec<String> {
+    CONFIG_FILE.lock().unwrap().errors.clone()
+}
+
+fn set_config_file_errors(errors: Vec<String>) {
+    for err in errors.iter() {
+        error!("daemon-config.yaml: {}", err);
+    }
+    CONFIG_FILE.lock().unwrap().errors = errors;
+}
+
+pub fn subscribe_index_events() -> tokio::sync::broadcast::Receiver<Uuid> {
+    INDEX_EVENTS.subscribe()
+}
 
 #[derive(Clone)]
 pub struct LocalData {
     pub installer_queue: tokio::sync::mpsc::Sender<InstallerQueueRequest>,
     pub config: ConfigData,
     pub map_index: Arc<Mutex<MapIndex>>,
+    /// Set while the map index gets rebuilt, it doesn't hold all maps of the installation until then
+    pub indexing: Arc<AtomicBool>,
 }
 
-pub type MapIndex = Vec<MapData>;
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct MapIndex {
+    pub maps: Vec<MapData>,
+    #[serde(default)]
+    pub fingerprints: HashMap<PathBuf, MapFingerprint>,
+}
 
-#[derive(Clone, Serialize, Deserialize)]
+/// Map index files used to be a plain list of maps, later without local map metadata
+#[derive(Deserialize)]
 #[serde(untagged)]
+enum StoredMapIndex {
+    Current(MapIndex),
+    Fingerprinted {
+        maps: Vec<LegacyMapData>,
+        fingerprints: HashMap<PathBuf, MapFingerprint>,
+    },
+    Legacy(Vec<LegacyMapData>),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "status", content = "data")]
 pub enum MapData {
     Valid(MapMetadata),
-    Unknown(std::path::PathBuf, String),
+    Unknown(UnknownMap),
     Invalid(std::path::PathBuf),
 }
 
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct MapMetadata {
     pub path: std::path::PathBuf,
     pub hash: String,
     pub id: u32,
+    pub info: Option<LocalMapInfo>,
+    #[serde(default)]
+    pub ranked: bool,
+    #[serde(default)]
+    pub qualified: bool,
+    #[serde(default)]
+    pub automapper: bool,
+    pub uploaded: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnknownMap {
+    pub path: std::path::PathBuf,
+    pub hash: String,
+    pub info: Option<LocalMapInfo>,
+    #[serde(default)]
+    pub reason: UnknownReason,
+    #[serde(default)]
+    pub last_checked: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+/// Why a map couldn't be resolved on BeatSaver
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum UnknownReason {
+    NotFound,
+    StatusCode(u16),
+    RequestFailed(String),
+    InvalidResponse(String),
+}
+
+// `#[default]` on variants would need Rust 1.62
+#[allow(clippy::derivable_impls)]
+impl Default for UnknownReason {
+    fn default() -> Self {
+        UnknownReason::NotFound
+    }
+}
+
+impl From<&BeatSaverError> for UnknownReason {
+    fn from(error: &BeatSaverError) -> Self {
+        match error {
+            BeatSaverError::StatusCodeError(404) => UnknownReason::NotFound,
+            BeatSaverError::StatusCodeError(code) => UnknownReason::StatusCode(*code),
+            BeatSaverError::RequestError(err, _) => UnknownReason::RequestFailed(err.to_string()),
+            BeatSaverError::JsonError(err, _, _) => UnknownReason::InvalidResponse(err.to_string())
+        }
+    }
+}
+
+impl UnknownReason {
+    /// Whether the lookup failed because of a temporary issue rather than the map missing on BeatSaver
+    pub fn is_transient(&self) -> bool {
+        match self {
+            UnknownReason::NotFound => false,
+            UnknownReason::StatusCode(code) => *code == 429 || *code >= 500,
+            UnknownReason::RequestFailed(_) => true,
+            UnknownReason::InvalidResponse(_) => true
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LegacyMapData {
+    Valid {
+        path: std::path::PathBuf,
+        hash: String,
+        id: u32,
+    },
+    Unknown(std::path::PathBuf, String),
+    Invalid(std::path::PathBuf),
 }
 
 #[derive(Clone)]
 pub struct DaemonConfig {
-    pub concurrent_downloads: u8,
+    pub settings: DaemonSettings,
     current_configs: Arc<Mutex<HashMap<Uuid, LocalData>>>,
     download_queue: tokio::sync::mpsc::Sender<DownloadQueueRequest>,
+    pub auth: Auth,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
 pub enum AuditLogAction {
     MapInstall(String),
     ModInstall(String),
     MapDelete(String),
     ModDelete(String),
+    Shutdown,
+    Restart,
+    ReloadConfig,
+    Reindex(Uuid),
 }
 
 impl DaemonConfig {
     pub fn new(download_queue: tokio::sync::mpsc::Sender<DownloadQueueRequest>) -> DaemonConfig {
         DaemonConfig {
-            concurrent_downloads: 4,
+            settings: DaemonSettings::read_from_file(),
             current_configs: Arc::new(Mutex::new(DaemonConfig::read_from_file())),
             download_queue,
+            auth: Auth::new(),
         }
     }
 
+    /// Loads the valid installations, the file is only normalized if all of it could be read
     fn read_from_file() -> HashMap<Uuid, LocalData> {
-        debug!("Reading config from file...");
-        let mut path = env::current_dir().unwrap().clone();
-        path.push("daemon-config.yaml");
         let mut vec = Vec::new();
-        match File::open(path.clone()) {
-            Ok(mut file) => {
-                let mut contents = String::new();
-                file.read_to_string(&mut contents).ok();
-                match YamlLoader::load_from_str(contents.as_str()) {
-                    Ok(docs) => {
-                        for yaml in docs {
-                            if let Ok(config) = DaemonConfig::read_yaml_doc(yaml) {
-                                let data: LocalData = config.into();
-                                let mut inner_data = data.clone();
-                                tokio::spawn(async move {
-                                    let mutex = inner_data.map_index.lock().await;
-                                    let empty = mutex.is_empty();
-                                    drop(mutex);
-                                    if empty {
-                                        inner_data.update_map_index(false).await;
-                                    }
-                                });
-                                vec.push(data);
-                            }
-                        }
-                        DaemonConfig::write_to_file(vec.clone().into_iter()
-                            .map(|data| data.config)
-                            .collect());
-                    }
-                    Err(error) => {
-                        warn!("Invalid yaml configuration: {}", error);
-                    }
+        match DaemonConfig::read_config_file() {
+            Ok((configs, errors, _)) => {
+                for config in configs {
+                    vec.push(DaemonConfig::load_installation(config));
+                }
+                if errors.is_empty() {
+                    DaemonConfig::write_to_file(vec.clone().into_iter()
+                        .map(|data| data.config)
+                        .collect());
+                } else {
+                    set_config_file_errors(errors);
                 }
             }
-            Err(err) => {
-                warn!("Couldn't open configuration file {}: {}", path.display(), err);
-            }
+            Err(err) => warn!("{}", err)
         }
         vec.into_iter()
             .map(|local_data| (local_data.config.id, local_data))
             .collect()
     }
 
-    fn read_yaml_doc(yaml: Yaml) -> Result<ConfigData, ()> {
+    fn config_file_path() -> PathBuf {
+        let mut path = env::current_dir().unwrap();
+        path.push("daemon-config.yaml");
+        path
+    }
+
+    /// Reads the installations of daemon-config.yaml along with the problems of documents which
+    /// couldn't be read and whether any installation got a new id. Fails only if the file itself can't be read.
+    fn read_config_file() -> Result<(Vec<ConfigData>, Vec<String>, bool), String> {
+        debug!("Reading config from file...");
+        let path = DaemonConfig::config_file_path();
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Couldn't open configuration file {}: {}", path.display(), err))?;
+        let docs = match YamlLoader::load_from_str(contents.as_str()) {
+            Ok(docs) => docs,
+            Err(error) => return Ok((Vec::new(), vec![format!("Invalid yaml configuration: {}", error)], false))
+        };
+        CONFIG_FILE.lock().unwrap().settings = Some(DaemonSettings::from_docs(docs.as_slice()));
+        let mut configs: Vec<ConfigData> = Vec::new();
+        let mut errors = Vec::new();
+        let mut generated_ids = false;
+        for (index, yaml) in docs.into_iter().enumerate() {
+            if !yaml["daemon"].is_badvalue() {
+                continue;
+            }
+            generated_ids |= yaml["id"].is_badvalue();
+            match DaemonConfig::read_yaml_doc(yaml) {
+                Ok(config) if configs.iter().any(|other| other.id.eq(&config.id)) => {
+                    errors.push(format!("Document {}: installation {} is configured twice", index + 1, config.id));
+                }
+                Ok(config) => configs.push(config),
+                Err(err) => errors.push(format!("Document {}: {}", index + 1, err))
+            }
+        }
+        Ok((configs, errors, generated_ids))
+    }
+
+    /// Creates the local data of an installation and indexes its maps if the index is empty
+    fn load_installation(config: ConfigData) -> LocalData {
+        let data: LocalData = config.into();
+        let mut inner_data = data.clone();
+        tokio::spawn(async move {
+            let mutex = inner_data.map_index.lock().await;
+            let empty = mutex.maps.is_empty();
+            drop(mutex);
+            if empty {
+                inner_data.update_map_index(false).await;
+            }
+        });
+        data
+    }
+
+    /// Re-reads daemon-config.yaml: new installations get loaded, removed ones stopped and
+    /// installations whose type or location changed get re-indexed.
+    /// Nothing is applied if any part of the file is invalid, daemon settings need a restart.
+    pub async fn reload_from_file(&self) -> Result<Vec<ConfigData>, Vec<String>> {
+        let (configs, generated_ids) = match DaemonConfig::read_config_file() {
+            Ok((configs, errors, generated_ids)) if errors.is_empty() => (configs, generated_ids),
+            Ok((_, errors, _)) => {
+                set_config_file_errors(errors.clone());
+                return Err(errors);
+            }
+            Err(err) => {
+                error!("{}", err);
+                return Err(vec![err]);
+            }
+        };
+        set_config_file_errors(Vec::new());
+        let mut needs_update = Vec::new();
+        let mut mutex = self.current_configs.lock().await;
+        mutex.retain(|id, _| {
+            let keep = configs.iter().any(|config| config.id.eq(id));
+            if !keep {
+                info!("Installation {} was removed", id);
+                crate::supervisor::stop_installation(id);
+            }
+            keep
+        });
+        for config_data in configs {
+            match mutex.get_mut(&config_data.id) {
+                Some(local_data) if config_data.install_type == local_data.config.install_type &&
+                    config_data.install_location.eq(&local_data.config.install_location) &&
+                    config_data.map_folder.eq(&local_data.config.map_folder) => {
+                    // the installer queue and watcher hold their own copy, so changes need a new installation
+                    if serde_json::to_value(&config_data).ok().ne(&serde_json::to_value(&local_data.config).ok()) {
+                        info!("Installation {} changed, restarting it", config_data.id);
+                        *local_data = config_data.into();
+                    }
+                }
+                Some(_) => {
+                    info!("Installation {} moved, restarting it", config_data.id);
+                    needs_update.push(config_data.id);
+                    let local: LocalData = config_data.into();
+                    local.indexing.store(true, Ordering::SeqCst);
+                    mutex.insert(local.config.id, local);
+                }
+                None => {
+                    info!("Installation {} was added", config_data.id);
+                    let local = DaemonConfig::load_installation(config_data);
+                    mutex.insert(local.config.id, local);
+                }
+            }
+        }
+        drop(mutex);
+        if generated_ids {
+            // otherwise the installations without an id would get a new one on every reload
+            DaemonConfig::write_to_file(self.get_configs().await);
+        }
+        for uuid in needs_update {
+            if let Some(mut local_data) = self.get_local_data(&uuid).await {
+                let mut index_lock = local_data.map_index.lock().await;
+                index_lock.maps.clear();
+                index_lock.fingerprints.clear();
+                drop(index_lock);
+                local_data.update_map_index(true).await;
+            }
+        }
+        info!("Reloaded config from file");
+        Ok(self.get_configs().await)
+    }
+
+    /// Reloads daemon-config.yaml unless it still holds what the daemon wrote itself
+    pub async fn reload_if_changed(&self) {
+        let contents = std::fs::read_to_string(DaemonConfig::config_file_path()).ok();
+        if contents.is_some() && CONFIG_FILE.lock().unwrap().written.eq(&contents) {
+            debug!("daemon-config.yaml is unchanged");
+            return;
+        }
+        info!("daemon-config.yaml changed, reloading it");
+        self.reload_from_file().await.ok();
+    }
+
+    pub async fn audit_log_entry(&self, action: AuditLogAction) {
+        crate::audit_log::append(None, action);
+    }
+
+    fn read_yaml_doc(yaml: Yaml) -> Result<ConfigData, String> {
         if let Some(map) = yaml.as_hash() {
-            let id = map.get(&Yaml::String("id".to_string()))
-                .and_then(|yaml| yaml.as_str())
-                .map(|str| uuid::Uuid::from_str(str).unwrap())
-                .unwrap_or_else(|| uuid::Uuid::new_v4());
+            let id = match map.get(&Yaml::String("id".to_string())).and_then(|yaml| yaml.as_str()) {
+                Some(str) => uuid::Uuid::from_str(str)
+                    .map_err(|err| format!("Invalid id {}: {}", str, err))?,
+                None => uuid::Uuid::new_v4()
+            };
             let rest_token = map.get(&Yaml::String("restToken".to_string()))
                 .and_then(|yaml| yaml.as_str())
                 .map(|str| str.to_string());
-            let install_type = map.get(&Yaml::String("installType".to_string()))
-                .and_then(|yaml| yaml.as_str())
-                .and_then(|str| InstallType::from_str(str).ok());
+            let install_type = match map.get(&Yaml::String("installType".to_string())).and_then(|yaml| yaml.as_str()) {
+                Some(str) => Some(InstallType::from_str(str)
+                    .map_err(|_| format!("Invalid installType {}, expected PC or Quest", str))?),
+                None => None
+            };
             let install_location = map.get(&Yaml::String("installLocation".to_string()))
                 .and_then(|yaml| yaml.as_str())
                 .map(|str| str.to_string());
+            let map_folder = map.get(&Yaml::String("mapFolder".to_string()))
+                .and_then(|yaml| yaml.as_str())
+                .map(|str| str.to_string());
+            let mirror = map.get(&Yaml::String("mirrorTarget".to_string()))
+                .and_then(|yaml| yaml.as_str())
+                .and_then(|str| uuid::Uuid::from_str(str).ok())
+                .map(|target| MirrorConfig {
+                    target,
+                    mode: match map.get(&Yaml::String("mirrorMode".to_string())).and_then(|yaml| yaml.as_str()) {
+                        Some("TwoWay") => SyncMode::TwoWay,
+                        _ => SyncMode::OneWay
+                    },
+                    delete: map.get(&Yaml::String("mirrorDeletes".to_string()))
+                        .and_then(|yaml| yaml.as_bool())
+                        .unwrap_or(false),
+                });
+            let routing = map.get(&Yaml::String("routing".to_string()))
+                .map(RoutingRules::from_yaml)
+                .unwrap_or_default();
+            let quota = map.get(&Yaml::String("quota".to_string()))
+                .map(QuotaConfig::from_yaml)
+                .unwrap_or_default();
+            let missing: Vec<&str> = [("restToken", rest_token.is_none()), ("installType", install_type.is_none()),
+                ("installLocation", install_location.is_none())].iter()
+                .filter(|(_, missing)| *missing)
+                .map(|(key, _)| *key)
+                .collect();
             if let Some(((rest_token, install_type), install_location)) = rest_token
                 .zip(install_type)
                 .zip(install_location) {
@@ -131,31 +424,56 @@ impl DaemonConfig {
                     rest_token,
                     install_type,
                     install_location,
+                    map_folder,
+                    mirror,
+                    routing,
+                    quota,
                 });
             }
+            return Err(format!("Installation {} is missing {}", id, missing.join(", ")));
         }
-        Err(())
+        Err("Expected an installation or the daemon settings".to_string())
     }
 
     fn write_to_file(configs: Vec<ConfigData>) {
+        let errors = config_file_errors();
+        if !errors.is_empty() {
+            error!("Not writing daemon-config.yaml until its errors are fixed: {}", errors.join("; "));
+            return;
+        }
         info!("Writing changed config to file...");
         let mut out_str = String::new();
         let mut emitter = YamlEmitter::new(&mut out_str);
         emitter.compact(false);
+        let settings = CONFIG_FILE.lock().unwrap().settings.clone().unwrap_or_default();
+        emitter.dump(&settings.to_yaml()).expect("Failed to write config");
         for config_data in configs {
             let mut hash = yaml_rust::yaml::Hash::new();
             hash.insert(Yaml::String("id".to_owned()), Yaml::String(config_data.id.to_hyphenated().to_string()));
             hash.insert(Yaml::String("restToken".to_owned()), Yaml::String(config_data.rest_token.clone()));
             hash.insert(Yaml::String("installType".to_owned()), Yaml::String(config_data.install_type.to_string()));
             hash.insert(Yaml::String("installLocation".to_owned()), Yaml::String(config_data.install_location.clone()));
+            if let Some(map_folder) = config_data.map_folder.as_ref() {
+                hash.insert(Yaml::String("mapFolder".to_owned()), Yaml::String(map_folder.clone()));
+            }
+            if let Some(mirror) = config_data.mirror.as_ref() {
+                hash.insert(Yaml::String("mirrorTarget".to_owned()), Yaml::String(mirror.target.to_hyphenated().to_string()));
+                hash.insert(Yaml::String("mirrorMode".to_owned()), Yaml::String(match mirror.mode {
+                    SyncMode::OneWay => "OneWay",
+                    SyncMode::TwoWay => "TwoWay"
+                }.to_owned()));
+                hash.insert(Yaml::String("mirrorDeletes".to_owned()), Yaml::Boolean(mirror.delete));
+            }
+            hash.insert(Yaml::String("routing".to_owned()), config_data.routing.to_yaml());
+            hash.insert(Yaml::String("quota".to_owned()), config_data.quota.to_yaml());
             let yaml = Yaml::Hash(hash);
             emitter.dump(&yaml).expect("Failed to write config");
         }
 
-        let mut path = env::current_dir().unwrap().clone();
-        path.push("daemon-config.yaml");
-        if let Ok(mut file) = File::create(path) {
-            file.write_all(out_str.replace("---", "\n---").as_bytes()).ok(); // great lib ngl...
+        let out_str = out_str.replace("---", "\n---"); // great lib ngl...
+        CONFIG_FILE.lock().unwrap().written = Some(out_str.clone());
+        if let Ok(mut file) = File::create(DaemonConfig::config_file_path()) {
+            file.write_all(out_str.as_bytes()).ok();
             info!("Done");
         } else {
             error!("An error occurred when writing file to system");
@@ -170,10 +488,23 @@ impl DaemonConfig {
         file_name.push_str(".json");
         path.push(file_name);
         if let Ok(data) = std::fs::read(path) {
-            if let Ok(map_index) = serde_json::from_slice(data.as_ref()) {
-                Some(map_index)
-            } else {
-                None
+            match serde_json::from_slice(data.as_ref()) {
+                Ok(StoredMapIndex::Current(map_index)) => Some(map_index),
+                Ok(StoredMapIndex::Fingerprinted { maps, fingerprints }) => {
+                    info!("Migrating map index of {}", id);
+                    Some(MapIndex {
+                        maps: maps.into_iter().map(|map| map.into()).collect(),
+                        fingerprints,
+                    })
+                }
+                Ok(StoredMapIndex::Legacy(maps)) => {
+                    info!("Migrating legacy map index of {}", id);
+                    Some(MapIndex {
+                        maps: maps.into_iter().map(|map| map.into()).collect(),
+                        fingerprints: HashMap::new(),
+                    })
+                }
+                Err(_) => None
             }
         } else {
             None
@@ -193,6 +524,8 @@ impl DaemonConfig {
         } else {
             error!("An error occurred when writing map index file to system");
         }
+        crate::status::record_index(id);
+        INDEX_EVENTS.send(*id).ok();
     }
 
     pub async fn update_configs(&self, configs: Vec<ConfigData>) -> Vec<ConfigData> {
@@ -202,7 +535,8 @@ impl DaemonConfig {
             for (id, local_data) in mutex.iter_mut() {
                 if id.eq(&config_data.id) {
                     if config_data.install_type != local_data.config.install_type ||
-                        config_data.install_location.ne(&local_data.config.install_location) {
+                        config_data.install_location.ne(&local_data.config.install_location) ||
+                        config_data.map_folder.ne(&local_data.config.map_folder) {
                         // reset map index if the installation type or location changed
                         needs_update.push(id.clone());
                     }
@@ -210,6 +544,9 @@ impl DaemonConfig {
                 }
             }
             let local: LocalData = config_data.into();
+            if needs_update.contains(&local.config.id) {
+                local.indexing.store(true, Ordering::SeqCst);
+            }
             mutex.insert(local.config.id.clone(), local);
         }
         drop(mutex);
@@ -219,7 +556,8 @@ impl DaemonConfig {
             let mut mutex = self.current_configs.lock().await;
             if let Some(config) = mutex.get_mut(&uuid) {
                 let mut index_lock = config.map_index.lock().await;
-                index_lock.clear();
+                index_lock.maps.clear();
+                index_lock.fingerprints.clear();
                 drop(index_lock);
                 config.update_map_index(true).await;
             }
@@ -236,6 +574,11 @@ impl DaemonConfig {
         vec
     }
 
+    pub async fn get_local_data(&self, id: &Uuid) -> Option<LocalData> {
+        let mutex = self.current_configs.lock().await;
+        mutex.get(id).cloned()
+    }
+
     pub async fn get_data(&self) -> Vec<LocalData> {
         let mutex = self.current_configs.lock().await;
         let mut vec = Vec::new();
@@ -248,6 +591,10 @@ impl DaemonConfig {
     pub async fn queue_map(&self, map: String) -> Result<(), tokio::sync::mpsc::error::SendError<DownloadQueueRequest>> {
         self.download_queue.send(DownloadQueueRequest::Map(map)).await
     }
+
+    pub async fn queue_map_for(&self, map: String, installations: Vec<Uuid>) -> Result<(), tokio::sync::mpsc::error::SendError<DownloadQueueRequest>> {
+        self.download_queue.send(DownloadQueueRequest::MapFor(map, installations)).await
+    }
 }
 
 impl LocalData {
@@ -255,69 +602,94 @@ impl LocalData {
         match self.config.install_type {
             InstallType::PC => {
                 let mut map_index = self.map_index.lock().await;
-                let mut buf = PathBuf::from(self.config.install_location.clone());
-                buf.push("Beat Saber_Data");
-                buf.push("CustomLevels");
-                let result = match crate::map_index::index_maps(buf, aggressive).await {
-                    Ok(vec) => {
-                        let mut errors = Vec::new();
-                        for result in vec {
-                            let error = match result {
-                                Ok((path, hash)) => {
-                                    match crate::beatsaver::resolve_map_by_hash(hash.as_str()).await {
-                                        Ok(data) => {
-                                            map_index.push(MapData::Valid(MapMetadata {
-                                                path,
-                                                hash,
-                                                id: u32::from_str_radix(data.id.as_str(), 16).expect("Map id is not hex, wtf?"),
-                                            }))
+                self.indexing.store(true, Ordering::SeqCst);
+                let mut previous_maps = std::mem::take(&mut map_index.maps)
+                    .into_iter()
+                    .map(|entry| (entry.as_ref().clone(), entry))
+                    .collect::<HashMap<PathBuf, MapData>>();
+                let previous_fingerprints = std::mem::take(&mut map_index.fingerprints);
+                let mut errors = Vec::new();
+                let mut changed = Vec::new();
+                for folder in crate::song_core::map_folders(self.config.install_location.as_str()) {
+                    if !folder.path.is_dir() {
+                        debug!("Skipping missing map folder {}", folder.path.display());
+                        continue;
+                    }
+                    match crate::map_index::list_map_directories(folder.path).await {
+                        Ok(entries) => {
+                            for entry in entries {
+                                match entry {
+                                    Ok(path) => {
+                                        let fingerprint = crate::map_index::fingerprint(path.as_path()).ok();
+                                        let unchanged = fingerprint.is_some() &&
+                                            previous_fingerprints.get(&path).eq(&fingerprint.as_ref());
+                                        if let Some(fingerprint) = fingerprint {
+                                            map_index.fingerprints.insert(path.clone(), fingerprint);
                                         }
-                                        Err(error) => {
-                                            match error {
-                                                BeatSaverError::RequestError(err, _) => error!("Unexpected request error: {}", err),
-                                                BeatSaverError::StatusCodeError(_) => map_index.push(MapData::Unknown(path, hash)),
-                                                BeatSaverError::JsonError(err, _, _) => error!("Unexpected json error: {}", err)
-                                            }
+                                        match previous_maps.remove(&path) {
+                                            Some(entry) if unchanged && entry.is_complete() => map_index.maps.push(entry),
+                                            Some(entry) => changed.push((path, Some(entry))),
+                                            None => changed.push((path, None))
                                         }
                                     }
-                                    None
+                                    Err(err) => errors.push(err)
                                 }
-                                Err(error) => {
-                                    match error {
-                                        IndexError::NotAMap(_, path) => {
-                                            map_index.push(MapData::Invalid(path));
-                                            None
-                                        }
-                                        IndexError::MapJsonError(_, path) => {
-                                            map_index.push(MapData::Invalid(path));
-                                            None
-                                        }
-                                        IndexError::InvalidMapInfoDat(path) => {
-                                            map_index.push(MapData::Invalid(path));
-                                            None
-                                        }
-                                        IndexError::InvalidDifficulty(_, path) => {
-                                            map_index.push(MapData::Invalid(path));
-                                            None
+                            }
+                        }
+                        Err(err) => errors.push(err)
+                    }
+                }
+                info!("Indexing {} new or changed maps, {} are unchanged", changed.len(), map_index.maps.len());
+                let (paths, mut previous_entries): (Vec<PathBuf>, Vec<Option<MapData>>) = changed.into_iter().unzip();
+                // entries of removed folders can still be reused if the map was just moved
+                previous_entries.extend(previous_maps.into_values().map(Some));
+                let results = crate::map_index::index_map_directories(paths.clone(), aggressive).await;
+                for (directory, result) in paths.into_iter().zip(results) {
+                    let error = match result {
+                        Ok((path, hash)) => {
+                            let previous = previous_entries.iter()
+                                .flatten()
+                                .find(|entry| entry.has_hash(hash.as_str()) && entry.is_complete())
+                                .cloned();
+                            if let Some(previous) = previous {
+                                map_index.maps.push(previous.with_path(path));
+                            } else {
+                                let info = crate::map_index::read_map_info(path.as_path()).ok();
+                                match crate::beatsaver::resolve_map_by_hash(hash.as_str()).await {
+                                    Ok(data) => {
+                                        map_index.maps.push(MapData::Valid(MapMetadata::new(path, hash, &data, info)))
+                                    }
+                                    Err(error) => {
+                                        match error {
+                                            BeatSaverError::RequestError(ref err, _) => error!("Unexpected request error: {}", err),
+                                            BeatSaverError::StatusCodeError(_) => {}
+                                            BeatSaverError::JsonError(ref err, _, _) => error!("Unexpected json error: {}", err)
                                         }
-                                        err => Some(err)
+                                        map_index.maps.push(MapData::Unknown(UnknownMap::new(path, hash, info, &error)));
                                     }
                                 }
-                            };
-                            if let Some(error) = error {
-                                errors.push(error);
                             }
+                            None
                         }
-                        if errors.len() == 0 {
+                        // the errors point at the broken file, the index and the repair work on the map folder
+                        Err(IndexError::NotAMap(_, _)) | Err(IndexError::MapJsonError(_, _)) |
+                        Err(IndexError::InvalidMapInfoDat(_)) | Err(IndexError::InvalidDifficulty(_, _)) => {
+                            map_index.maps.push(MapData::Invalid(directory));
                             None
-                        } else {
-                            Some(errors)
                         }
+                        Err(err) => Some(err)
+                    };
+                    if let Some(error) = error {
+                        errors.push(error);
                     }
-                    Err(err) => Some(vec![err])
-                };
+                }
                 DaemonConfig::write_map_index_to_file(&self.config.id, &map_index);
-                result
+                self.indexing.store(false, Ordering::SeqCst);
+                if errors.is_empty() {
+                    None
+                } else {
+                    Some(errors)
+                }
             }
             InstallType::Quest => {
                 info!("Quest Map index is not supported yet");
@@ -332,39 +704,55 @@ impl LocalData {
         DaemonConfig::write_map_index_to_file(&self.config.id, &mutex)
     }
 
+    /// Drops the entries of removed map folders and persists the index
+    pub async fn remove_from_index(&self, paths: &[PathBuf]) {
+        let mut index = self.map_index.lock().await;
+        index.maps.retain(|entry| !paths.contains(entry.as_ref()));
+        for path in paths {
+            index.fingerprints.remove(path);
+        }
+        DaemonConfig::write_map_index_to_file(&self.config.id, &index);
+    }
+
     pub async fn is_map_installed(&self, hash: &str) -> bool {
-        let vec = self.map_index.lock().await;
-        vec.iter().any(|data| data.has_hash(hash))
+        let index = self.map_index.lock().await;
+        index.maps.iter().any(|data| data.has_hash(hash))
     }
 
     pub async fn is_map_installed_by_id(&self, id: &str) -> bool {
-        let vec = self.map_index.lock().await;
-        vec.iter().any(|data| data.has_id(id))
+        let index = self.map_index.lock().await;
+        index.maps.iter().any(|data| data.has_id(id))
     }
 
-    pub async fn audit_log_entry(&self, _action: AuditLogAction) {
-        // todo
+    pub async fn audit_log_entry(&self, action: AuditLogAction) {
+        crate::audit_log::append(Some(self.config.id), action);
     }
 }
 
 impl Into<LocalData> for ConfigData {
     fn into(self) -> LocalData {
-        let (installer_queue_tx, installer_queue_rx) = tokio::sync::mpsc::channel(1024);
+        let (installer_queue_tx, installer_queue_rx) = tokio::sync::mpsc::channel(INSTALLER_QUEUE_SIZE);
         let map_index = Arc::new(Mutex::new(DaemonConfig::read_map_index_from_file(&self.id)
             .unwrap_or_default()));
         let data = LocalData {
             installer_queue: installer_queue_tx,
             config: self,
             map_index,
+            indexing: Arc::new(AtomicBool::new(false)),
         };
-        InstallerQueue::new(installer_queue_rx, data.clone())
-            .start(); // todo: can we catch this join handle somehow and make sure it doesnt die
+        let id = data.config.id;
+        let queue = InstallerQueue::new(installer_queue_rx, data.clone());
+        crate::supervisor::supervise(crate::supervisor::installer_queue_task(&id), Some(id),
+                                     move || Ok(queue.clone().start()));
         // one a map/mod is sent into the installer queue, it is hard to track its
         // success state programmatically:
         match data.config.install_type {
             InstallType::PC => {
-                PcMapsWatcher::new(data.clone()).start_watcher()
-                    .expect("Failed to start maps watcher"); // todo: can we catch this join handle somehow and make sure it doesnt die
+                let watched = data.clone();
+                crate::supervisor::supervise(crate::supervisor::watcher_task(&id), Some(id), move || {
+                    PcMapsWatcher::new(watched.clone()).start_watcher()
+                        .map_err(|err| format!("Failed to start maps watcher: {}", err))
+                });
             }
             InstallType::Quest => {
                 info!("QuestMapsWatcher is not implemented yet!");
@@ -388,17 +776,116 @@ impl AsRef<PathBuf> for MapData {
     fn as_ref(&self) -> &PathBuf {
         match self {
             MapData::Valid(meta) => &meta.path,
-            MapData::Unknown(path, _) => path,
+            MapData::Unknown(map) => &map.path,
             MapData::Invalid(path) => path
         }
     }
 }
 
+impl From<LegacyMapData> for MapData {
+    fn from(legacy: LegacyMapData) -> Self {
+        match legacy {
+            LegacyMapData::Valid { path, hash, id } => MapData::Valid(MapMetadata {
+                path,
+                hash,
+                id,
+                info: None,
+                ranked: false,
+                qualified: false,
+                automapper: false,
+                uploaded: None,
+            }),
+            LegacyMapData::Unknown(path, hash) => MapData::Unknown(UnknownMap {
+                path,
+                hash,
+                info: None,
+                reason: UnknownReason::NotFound,
+                last_checked: None,
+                attempts: 0,
+            }),
+            LegacyMapData::Invalid(path) => MapData::Invalid(path)
+        }
+    }
+}
+
+impl MapMetadata {
+    pub fn new(path: PathBuf, hash: String, map: &BeatSaverMap, info: Option<LocalMapInfo>) -> MapMetadata {
+        let info = info.map(|mut info| {
+            if info.duration.is_none() {
+                info.duration = Some(map.metadata.duration as f32);
+            }
+            info
+        });
+        MapMetadata {
+            path,
+            hash,
+            id: u32::from_str_radix(map.id.as_str(), 16).expect("Map id is not hex, wtf?"),
+            info,
+            ranked: map.ranked,
+            qualified: map.qualified,
+            automapper: map.automapper,
+            uploaded: map.uploaded,
+        }
+    }
+}
+
+impl UnknownMap {
+    pub fn new(path: PathBuf, hash: String, info: Option<LocalMapInfo>, error: &BeatSaverError) -> UnknownMap {
+        UnknownMap {
+            path,
+            hash,
+            info,
+            reason: error.into(),
+            last_checked: Some(Utc::now()),
+            attempts: 0,
+        }
+    }
+}
+
 impl MapData {
+    pub fn with_path(self, path: PathBuf) -> MapData {
+        match self {
+            MapData::Valid(meta) => MapData::Valid(MapMetadata {
+                path,
+                ..meta
+            }),
+            MapData::Unknown(map) => MapData::Unknown(UnknownMap {
+                path,
+                ..map
+            }),
+            MapData::Invalid(_) => MapData::Invalid(path)
+        }
+    }
+
+    pub fn status(&self) -> MapStatus {
+        match self {
+            MapData::Valid(_) => MapStatus::Valid,
+            MapData::Unknown(_) => MapStatus::Unknown,
+            MapData::Invalid(_) => MapStatus::Invalid
+        }
+    }
+
+    pub fn info(&self) -> Option<&LocalMapInfo> {
+        match self {
+            MapData::Valid(map) => map.info.as_ref(),
+            MapData::Unknown(map) => map.info.as_ref(),
+            MapData::Invalid(_) => None
+        }
+    }
+
+    /// Whether the entry has all metadata, entries from migrated index files don't
+    pub fn is_complete(&self) -> bool {
+        match self {
+            MapData::Valid(map) => map.info.is_some() && map.uploaded.is_some(),
+            MapData::Unknown(map) => map.info.is_some(),
+            MapData::Invalid(_) => true
+        }
+    }
+
     pub fn has_hash(&self, hash: &str) -> bool {
         match self {
             MapData::Valid(map) => map.hash.eq(hash),
-            MapData::Unknown(_, map_hash) => map_hash.eq(hash),
+            MapData::Unknown(map) => map.hash.eq(hash),
             MapData::Invalid(_) => false
         }
     }
@@ -0,0 +1,61 @@
+use crate::config::DaemonConfig;
+use log::{debug, info, warn};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use tokio::task::JoinHandle;
+
+/// Applies edits of daemon-config.yaml without a restart
+pub struct ConfigWatcher {
+    config: DaemonConfig,
+}
+
+/// Editors often replace the file instead of writing it, so the whole directory is watched
+fn is_config_file(event: &DebouncedEvent) -> bool {
+    let path = match event {
+        DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Rename(_, path) => path,
+        _ => return false
+    };
+    path.file_name().map(|name| name.eq("daemon-config.yaml")).unwrap_or(false)
+}
+
+impl ConfigWatcher {
+    pub fn new(config: DaemonConfig) -> ConfigWatcher {
+        ConfigWatcher {
+            config,
+        }
+    }
+
+    pub fn start(self) -> notify::Result<JoinHandle<()>> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = notify::watcher(tx, core::time::Duration::from_secs(2))?;
+        let directory = std::env::current_dir()?;
+        watcher.watch(directory.as_path(), RecursiveMode::NonRecursive)?;
+        info!("Watching {} for changes", directory.join("daemon-config.yaml").display());
+
+        // a pending reload covers every change before it
+        let (changed_tx, mut changed_rx) = tokio::sync::mpsc::channel(1);
+        // ends once the watcher is dropped and its sender with it
+        let wrapper_handle = tokio::task::spawn_blocking(move || {
+            while let Ok(event) = rx.recv() {
+                if is_config_file(&event) {
+                    debug!("daemon-config.yaml event: {:?}", event);
+                    changed_tx.try_send(()).ok();
+                }
+            }
+        });
+        Ok(tokio::spawn(async move {
+            // runs in the watcher task, so stopping that task drops the watcher
+            let _watcher = watcher;
+            let reloader = async {
+                while changed_rx.recv().await.is_some() {
+                    self.config.reload_if_changed().await;
+                }
+            };
+            tokio::select! {
+                _val = wrapper_handle => {
+                    warn!("Config watcher wrapper died!");
+                }
+                _val = reloader => {}
+            }
+        }))
+    }
+}
@@ -0,0 +1,253 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use log::{debug, warn};
+use yaml_rust::{Yaml, YamlLoader};
+
+pub const DEFAULT_PORT: u16 = 2706;
+pub const DEFAULT_LAN_PORT: u16 = 2707;
+const DEFAULT_CONCURRENT_DOWNLOADS: u8 = 4;
+const DEFAULT_ALLOWED_ORIGINS: [&str; 3] = ["https://beatsaver.com", "https://scoresaber.com", "https://aiosaber.zerotwo.workers.dev"];
+
+/// Settings of the daemon itself, stored in the `daemon` document of daemon-config.yaml
+/// and overridable with command line flags
+#[derive(Clone)]
+pub struct DaemonSettings {
+    pub address: IpAddr,
+    pub port: u16,
+    /// Origins allowed in addition to the default ones
+    pub allowed_origins: Vec<String>,
+    pub concurrent_downloads: u8,
+    pub lan: LanSettings,
+    /// Serves Prometheus metrics on `GET /metrics`
+    pub metrics: bool,
+}
+
+/// Optional second listener for other devices in the local network, always served over TLS
+#[derive(Clone)]
+pub struct LanSettings {
+    pub enabled: bool,
+    pub address: IpAddr,
+    pub port: u16,
+    /// PKCS#12 certificate with its private key, a self-signed one is generated if none is set
+    pub certificate: Option<PathBuf>,
+    pub certificate_password: String,
+    /// Advertises the daemon as `_aiosaber._tcp.local`
+    pub mdns: bool,
+}
+
+fn read_port(yaml: &Yaml, name: &str) -> Option<u16> {
+    let port = yaml.as_i64()?;
+    match u16::try_from(port) {
+        Ok(port) => Some(port),
+        Err(_) => {
+            warn!("Invalid {} {}, using the default", name, port);
+            None
+        }
+    }
+}
+
+impl Default for LanSettings {
+    fn default() -> Self {
+        LanSettings {
+            enabled: false,
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_LAN_PORT,
+            certificate: None,
+            certificate_password: String::new(),
+            mdns: true,
+        }
+    }
+}
+
+impl LanSettings {
+    fn from_yaml(yaml: &Yaml) -> LanSettings {
+        let defaults = LanSettings::default();
+        LanSettings {
+            enabled: yaml["enabled"].as_bool().unwrap_or(defaults.enabled),
+            address: yaml["address"].as_str()
+                .and_then(|address| IpAddr::from_str(address).ok())
+                .unwrap_or(defaults.address),
+            port: read_port(&yaml["port"], "LAN port").unwrap_or(defaults.port),
+            certificate: yaml["certificate"].as_str()
+                .map(PathBuf::from)
+                .or(defaults.certificate),
+            certificate_password: yaml["certificatePassword"].as_str()
+                .map(|password| password.to_string())
+                .unwrap_or(defaults.certificate_password),
+            mdns: yaml["mdns"].as_bool().unwrap_or(defaults.mdns),
+        }
+    }
+
+    fn to_yaml(&self) -> Yaml {
+        let mut lan = yaml_rust::yaml::Hash::new();
+        lan.insert(Yaml::String("enabled".to_owned()), Yaml::Boolean(self.enabled));
+        lan.insert(Yaml::String("address".to_owned()), Yaml::String(self.address.to_string()));
+        lan.insert(Yaml::String("port".to_owned()), Yaml::Integer(self.port as i64));
+        if let Some(certificate) = &self.certificate {
+            lan.insert(Yaml::String("certificate".to_owned()), Yaml::String(certificate.display().to_string()));
+            lan.insert(Yaml::String("certificatePassword".to_owned()), Yaml::String(self.certificate_password.clone()));
+        }
+        lan.insert(Yaml::String("mdns".to_owned()), Yaml::Boolean(self.mdns));
+        Yaml::Hash(lan)
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+}
+
+impl Default for DaemonSettings {
+    fn default() -> Self {
+        DaemonSettings {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            allowed_origins: Vec::new(),
+            concurrent_downloads: DEFAULT_CONCURRENT_DOWNLOADS,
+            lan: LanSettings::default(),
+            metrics: false,
+        }
+    }
+}
+
+impl DaemonSettings {
+    pub fn read_from_file() -> DaemonSettings {
+        let mut path = env::current_dir().unwrap();
+        path.push("daemon-config.yaml");
+        let mut contents = String::new();
+        if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)) {
+            debug!("No daemon settings at {}: {}", path.display(), err);
+            return DaemonSettings::default();
+        }
+        match YamlLoader::load_from_str(contents.as_str()) {
+            Ok(docs) => DaemonSettings::from_docs(docs.as_slice()),
+            Err(err) => {
+                warn!("Invalid yaml configuration: {}", err);
+                DaemonSettings::default()
+            }
+        }
+    }
+
+    /// Settings of the `daemon` document, defaults if there is none
+    pub fn from_docs(docs: &[Yaml]) -> DaemonSettings {
+        docs.iter()
+            .find(|doc| !doc["daemon"].is_badvalue())
+            .map(|doc| DaemonSettings::from_yaml(&doc["daemon"]))
+            .unwrap_or_default()
+    }
+
+    fn from_yaml(yaml: &Yaml) -> DaemonSettings {
+        let defaults = DaemonSettings::default();
+        DaemonSettings {
+            address: yaml["address"].as_str()
+                .and_then(|address| IpAddr::from_str(address).ok())
+                .unwrap_or(defaults.address),
+            port: read_port(&yaml["port"], "port").unwrap_or(defaults.port),
+            allowed_origins: yaml["allowedOrigins"].as_vec()
+                .map(|origins| origins.iter()
+                    .filter_map(|origin| origin.as_str())
+                    .map(|origin| origin.to_string())
+                    .collect())
+                .unwrap_or(defaults.allowed_origins),
+            concurrent_downloads: yaml["concurrentDownloads"].as_i64()
+                .map(|downloads| downloads.max(1).min(u8::MAX as i64) as u8)
+                .unwrap_or(defaults.concurrent_downloads),
+            lan: LanSettings::from_yaml(&yaml["lan"]),
+            metrics: yaml["metrics"].as_bool().unwrap_or(defaults.metrics),
+        }
+    }
+
+    /// The `daemon` document written in front of the installations
+    pub fn to_yaml(&self) -> Yaml {
+        let mut settings = yaml_rust::yaml::Hash::new();
+        settings.insert(Yaml::String("address".to_owned()), Yaml::String(self.address.to_string()));
+        settings.insert(Yaml::String("port".to_owned()), Yaml::Integer(self.port as i64));
+        settings.insert(Yaml::String("allowedOrigins".to_owned()), Yaml::Array(self.allowed_origins.iter()
+            .map(|origin| Yaml::String(origin.clone()))
+            .collect()));
+        settings.insert(Yaml::String("concurrentDownloads".to_owned()), Yaml::Integer(self.concurrent_downloads as i64));
+        settings.insert(Yaml::String("lan".to_owned()), self.lan.to_yaml());
+        settings.insert(Yaml::String("metrics".to_owned()), Yaml::Boolean(self.metrics));
+        let mut doc = yaml_rust::yaml::Hash::new();
+        doc.insert(Yaml::String("daemon".to_owned()), Yaml::Hash(settings));
+        Yaml::Hash(doc)
+    }
+
+    /// Applies `--address`, `--port`, `--allow-origin`, `--concurrent-downloads`, `--metrics` and the `--lan` flags
+    pub fn apply_args(&mut self, args: Vec<String>) {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.as_str() {
+                "--lan" => {
+                    self.lan.enabled = true;
+                    continue;
+                }
+                "--no-mdns" => {
+                    self.lan.mdns = false;
+                    continue;
+                }
+                "--metrics" => {
+                    self.metrics = true;
+                    continue;
+                }
+                "--address" | "--port" | "--allow-origin" | "--concurrent-downloads" |
+                "--lan-port" | "--lan-certificate" | "--lan-certificate-password" => args.next(),
+                _ => continue
+            };
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    warn!("{} is missing a value", arg);
+                    continue;
+                }
+            };
+            match arg.as_str() {
+                "--address" => match IpAddr::from_str(value.as_str()) {
+                    Ok(address) => self.address = address,
+                    Err(err) => warn!("Invalid address {}: {}", value, err)
+                },
+                "--port" => match value.parse() {
+                    Ok(port) => self.port = port,
+                    Err(err) => warn!("Invalid port {}: {}", value, err)
+                },
+                "--allow-origin" => self.allowed_origins.push(value),
+                "--concurrent-downloads" => match value.parse::<u8>() {
+                    Ok(downloads) => self.concurrent_downloads = downloads.max(1),
+                    Err(err) => warn!("Invalid amount of concurrent downloads {}: {}", value, err)
+                },
+                "--lan-port" => match value.parse() {
+                    Ok(port) => self.lan.port = port,
+                    Err(err) => warn!("Invalid LAN port {}: {}", value, err)
+                },
+                "--lan-certificate" => self.lan.certificate = Some(PathBuf::from(value)),
+                "--lan-certificate-password" => self.lan.certificate_password = value,
+                _ => {}
+            }
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    pub fn allowed_origins(&self) -> Vec<String> {
+        DEFAULT_ALLOWED_ORIGINS.iter()
+            .map(|origin| origin.to_string())
+            .chain(self.allowed_origins.iter().cloned())
+            .collect()
+    }
+
+    /// Base url the CLI uses to reach the running daemon
+    pub fn local_url(&self) -> String {
+        let host = if self.address.is_unspecified() {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        } else {
+            self.address
+        };
+        format!("http://{}", SocketAddr::new(host, self.port))
+    }
+}
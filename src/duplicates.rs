@@ -0,0 +1,188 @@
+use crate::config::{LocalData, MapData, MapIndex, AuditLogAction};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use log::{info, error};
+use uuid::Uuid;
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateReport {
+    pub installation: Uuid,
+    pub groups: Vec<DuplicateGroup>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum DuplicateKind {
+    /// The exact same map is installed multiple times
+    SameHash(String),
+    /// Multiple versions of the same BeatSaver map are installed
+    SameKey(String),
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateGroup {
+    pub kind: DuplicateKind,
+    pub maps: Vec<MapData>,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub enum CleanupPolicy {
+    NewestFolder,
+    ShortestPath,
+    InPlaylist,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CleanupRequest {
+    pub installation: Uuid,
+    pub policy: CleanupPolicy,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CleanupResult {
+    pub installation: Uuid,
+    pub kept: Vec<PathBuf>,
+    pub moved: Vec<PathBuf>,
+    pub errors: Vec<String>,
+}
+
+pub fn find_duplicates(installation: Uuid, index: &MapIndex) -> DuplicateReport {
+    let mut by_hash: HashMap<String, Vec<&MapData>> = HashMap::new();
+    let mut by_key: HashMap<u32, Vec<&MapData>> = HashMap::new();
+    for map in index.maps.iter() {
+        match map {
+            MapData::Valid(meta) => {
+                by_hash.entry(meta.hash.to_lowercase()).or_default().push(map);
+                by_key.entry(meta.id).or_default().push(map);
+            }
+            MapData::Unknown(unknown) => {
+                by_hash.entry(unknown.hash.to_lowercase()).or_default().push(map);
+            }
+            MapData::Invalid(_) => {}
+        }
+    }
+    let mut groups = by_hash.into_iter()
+        .filter(|(_, maps)| maps.len() > 1)
+        .map(|(hash, maps)| DuplicateGroup {
+            kind: DuplicateKind::SameHash(hash),
+            maps: maps.into_iter().cloned().collect(),
+        })
+        .collect::<Vec<DuplicateGroup>>();
+    groups.extend(by_key.into_iter()
+        .filter(|(_, maps)| {
+            let hashes = maps.iter()
+                .filter_map(|map| match map {
+                    MapData::Valid(meta) => Some(meta.hash.to_lowercase()),
+                    _ => None
+                })
+                .collect::<HashSet<String>>();
+            hashes.len() > 1
+        })
+        .map(|(id, maps)| DuplicateGroup {
+            kind: DuplicateKind::SameKey(format!("{:x}", id)),
+            maps: maps.into_iter().cloned().collect(),
+        }));
+    DuplicateReport {
+        installation,
+        groups,
+    }
+}
+
+fn folder_modified(index: &MapIndex, path: &PathBuf) -> u64 {
+    index.fingerprints.get(path)
+        .map(|fingerprint| fingerprint.modified)
+        .or_else(|| std::fs::metadata(path).ok()
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|duration| duration.as_millis() as u64))
+        .unwrap_or(0)
+}
+
+fn choose_kept<'a>(maps: &[&'a MapData], policy: CleanupPolicy, index: &MapIndex,
+                   playlist_hashes: &HashSet<String>) -> Option<&'a MapData> {
+    match policy {
+        CleanupPolicy::NewestFolder => maps.iter()
+            .max_by_key(|map| folder_modified(index, map.as_ref()))
+            .copied(),
+        CleanupPolicy::ShortestPath => maps.iter()
+            .min_by_key(|map| map.as_ref().as_os_str().len())
+            .copied(),
+        CleanupPolicy::InPlaylist => {
+            let in_playlist = maps.iter()
+                .filter(|map| match map {
+                    MapData::Valid(meta) => playlist_hashes.contains(&meta.hash.to_lowercase()),
+                    MapData::Unknown(unknown) => playlist_hashes.contains(&unknown.hash.to_lowercase()),
+                    MapData::Invalid(_) => false
+                })
+                .copied()
+                .collect::<Vec<&MapData>>();
+            if in_playlist.is_empty() {
+                choose_kept(maps, CleanupPolicy::NewestFolder, index, playlist_hashes)
+            } else {
+                choose_kept(in_playlist.as_slice(), CleanupPolicy::NewestFolder, index, playlist_hashes)
+            }
+        }
+    }
+}
+
+/// Keeps one map of every duplicate group and moves the others into the trash folder
+pub async fn cleanup_duplicates(local_data: &LocalData, policy: CleanupPolicy) -> CleanupResult {
+    let install_location = local_data.config.install_location.clone();
+    let playlist_hashes = crate::playlists::playlist_hashes(install_location.as_str());
+    let index = local_data.map_index.lock().await.clone();
+    let report = find_duplicates(local_data.config.id, &index);
+
+    let mut kept = Vec::new();
+    let mut removed: Vec<(PathBuf, String)> = Vec::new();
+    for group in report.groups.iter() {
+        let maps = group.maps.iter()
+            .filter(|map| !removed.iter().any(|(path, _)| path.eq(map.as_ref())))
+            .collect::<Vec<&MapData>>();
+        if maps.len() < 2 {
+            continue;
+        }
+        if let Some(keep) = choose_kept(maps.as_slice(), policy, &index, &playlist_hashes) {
+            kept.push(keep.as_ref().clone());
+            for map in maps {
+                if map.as_ref().ne(keep.as_ref()) {
+                    let hash = match map {
+                        MapData::Valid(meta) => meta.hash.clone(),
+                        MapData::Unknown(unknown) => unknown.hash.clone(),
+                        MapData::Invalid(_) => String::new()
+                    };
+                    removed.push((map.as_ref().clone(), hash));
+                }
+            }
+        }
+    }
+
+    let trash = crate::song_core::trash_folder(install_location.as_str());
+    let mut moved = Vec::new();
+    let mut errors = Vec::new();
+    for (path, hash) in removed {
+        match crate::song_core::move_map_folder(path.as_path(), trash.as_path()) {
+            Ok(target) => {
+                info!("Moved duplicate {} to {}", path.display(), target.display());
+                local_data.audit_log_entry(AuditLogAction::MapDelete(hash)).await;
+                moved.push(path);
+            }
+            Err(err) => {
+                error!("Cannot move duplicate {}: {}", path.display(), err);
+                errors.push(format!("{}: {}", path.display(), err));
+            }
+        }
+    }
+    local_data.remove_from_index(moved.as_slice()).await;
+    CleanupResult {
+        installation: local_data.config.id,
+        kept,
+        moved,
+        errors,
+    }
+}
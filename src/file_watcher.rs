@@ -1,58 +1,109 @@
-use crate::config::{LocalData, MapData, MapMetadata};
+use crate::config::{LocalData, MapData, MapMetadata, UnknownMap};
 use log::{debug, info, warn, error};
 use notify::{RecommendedWatcher, RecursiveMode, Watcher, DebouncedEvent};
 use tokio::task::JoinHandle;
 use std::path::PathBuf;
 use crate::map_index;
 use crate::beatsaver;
+use crate::song_core::MapFolder;
+
+/// How often map folders which didn't exist yet are checked, e.g. before the first start of the game
+const MISSING_FOLDER_CHECK_SECONDS: u64 = 30;
+
+/// Label of the event in the metrics
+fn event_kind(event: &DebouncedEvent) -> &'static str {
+    match event {
+        DebouncedEvent::NoticeWrite(_) => "notice_write",
+        DebouncedEvent::NoticeRemove(_) => "notice_remove",
+        DebouncedEvent::Create(_) => "create",
+        DebouncedEvent::Write(_) => "write",
+        DebouncedEvent::Chmod(_) => "chmod",
+        DebouncedEvent::Remove(_) => "remove",
+        DebouncedEvent::Rename(_, _) => "rename",
+        DebouncedEvent::Rescan => "rescan",
+        DebouncedEvent::Error(_, _) => "error",
+    }
+}
+
+fn watch_folder(watcher: &mut RecommendedWatcher, folder: &MapFolder) -> notify::Result<()> {
+    info!("Starting watcher for {}{} at {}", folder.name.as_str(),
+          if folder.wip { " (WIP)" } else { "" }, folder.path.display());
+    watcher.watch(folder.path.as_path(), RecursiveMode::NonRecursive)
+}
+
+/// Watches the missing folders which exist by now, returns whether any of them appeared
+fn watch_appeared_folders(watcher: &mut RecommendedWatcher, missing: &mut Vec<MapFolder>) -> bool {
+    let mut appeared = false;
+    missing.retain(|folder| {
+        if !folder.path.is_dir() {
+            return true;
+        }
+        match watch_folder(watcher, folder) {
+            Ok(_) => {
+                appeared = true;
+                false
+            }
+            Err(err) => {
+                warn!("Cannot watch map folder {}: {:?}", folder.path.display(), err);
+                true
+            }
+        }
+    });
+    appeared
+}
 
 pub struct PcMapsWatcher {
     config: LocalData,
-    path: PathBuf,
+    folders: Vec<MapFolder>,
 }
 
 impl PcMapsWatcher {
     pub fn new(config: LocalData) -> PcMapsWatcher {
-        let mut path = std::path::PathBuf::from(config.config.install_location.clone());
-        path.push("Beat Saber_Data");
-        path.push("CustomLevels");
+        let folders = crate::song_core::map_folders(config.config.install_location.as_str());
         PcMapsWatcher {
             config,
-            path,
+            folders,
         }
     }
 
     pub fn start_watcher(self) -> notify::Result<JoinHandle<()>> {
-        info!("Starting watcher at {}", self.path.display());
         let (tx, rx) = std::sync::mpsc::channel();
         let mut watcher = notify::watcher(tx, core::time::Duration::from_secs(5))?;
-        watcher.watch(self.path.as_path(), RecursiveMode::NonRecursive)?;
-        Ok(tokio::spawn(async move { self.start_receiver(rx, watcher).await; }))
+        let mut missing = Vec::new();
+        for folder in self.folders.iter() {
+            if folder.path.is_dir() {
+                watch_folder(&mut watcher, folder)?;
+            } else {
+                debug!("Map folder {} is missing, watching it once it exists", folder.path.display());
+                missing.push(folder.clone());
+            }
+        }
+        Ok(tokio::spawn(async move { self.start_receiver(rx, watcher, missing).await; }))
     }
 
     async fn handle_created(&self, config: LocalData, path: PathBuf) {
+        if let Ok(fingerprint) = map_index::fingerprint(path.as_path()) {
+            config.map_index.lock().await.fingerprints.insert(path.clone(), fingerprint);
+        }
         match map_index::generate_hash(path.clone()) {
             Ok(hash) => {
+                let info = map_index::read_map_info(path.as_path()).ok();
                 match beatsaver::resolve_map_by_hash(hash.as_str()).await {
                     Ok(map) => {
                         let mut mutex = config.map_index.lock().await;
-                        mutex.push(MapData::Valid(MapMetadata {
-                            path,
-                            hash,
-                            id: u32::from_str_radix(map.id.as_str(), 16).expect("Map id is not hex, wtf?"),
-                        }));
+                        mutex.maps.push(MapData::Valid(MapMetadata::new(path, hash, &map, info)));
                     }
                     Err(err) => {
                         warn!("Map seems to be not a beatsaver map {}: {:?}", path.display(), err);
                         let mut mutex = config.map_index.lock().await;
-                        mutex.push(MapData::Unknown(path, hash));
+                        mutex.maps.push(MapData::Unknown(UnknownMap::new(path, hash, info, &err)));
                     }
                 }
             }
             Err(err) => {
                 warn!("watcher: An indexing error occurred in {}: {:?}", path.display(), err);
                 let mut mutex = config.map_index.lock().await;
-                mutex.push(MapData::Invalid(path));
+                mutex.maps.push(MapData::Invalid(path));
             }
         }
         config.rewrite_map_index().await;
@@ -60,34 +111,24 @@ impl PcMapsWatcher {
 
     async fn handle_removed(&self, config: LocalData, path: PathBuf) {
         let mut mutex = config.map_index.lock().await;
-        mutex.retain(|entry| entry.as_ref().ne(&path));
+        mutex.maps.retain(|entry| entry.as_ref().ne(&path));
+        mutex.fingerprints.remove(&path);
         std::mem::drop(mutex);
         config.rewrite_map_index().await;
     }
 
     async fn handle_renamed(&self, config: LocalData, old: PathBuf, new: PathBuf) {
         let mut mutex = config.map_index.lock().await;
-        let old_data = mutex.iter()
+        let old_data = mutex.maps.iter()
             .find(|entry| entry.as_ref().eq(&old))
             .cloned();
-        mutex.retain(|entry| entry.as_ref().ne(&old));
+        mutex.maps.retain(|entry| entry.as_ref().ne(&old));
+        if let Some(fingerprint) = mutex.fingerprints.remove(&old) {
+            mutex.fingerprints.insert(new.clone(), fingerprint);
+        }
         let mut needs_rewrite = true;
         if let Some(old) = old_data {
-            match old {
-                MapData::Valid(meta) => {
-                    mutex.push(MapData::Valid(MapMetadata {
-                        path: new,
-                        hash: meta.hash,
-                        id: meta.id,
-                    }));
-                }
-                MapData::Unknown(_, hash) => {
-                    mutex.push(MapData::Unknown(new, hash));
-                }
-                MapData::Invalid(_) => {
-                    mutex.push(MapData::Invalid(new));
-                }
-            }
+            mutex.maps.push(old.with_path(new));
             std::mem::drop(mutex);
         } else {
             std::mem::drop(mutex); // make sure its gone
@@ -99,28 +140,41 @@ impl PcMapsWatcher {
         }
     }
 
-    async fn start_receiver(self, channel: std::sync::mpsc::Receiver<notify::DebouncedEvent>, watcher: RecommendedWatcher) {
+    async fn start_receiver(self, channel: std::sync::mpsc::Receiver<notify::DebouncedEvent>, watcher: RecommendedWatcher,
+                            mut missing: Vec<MapFolder>) {
         let config = self.config.clone();
         let (tx, mut rx) = tokio::sync::mpsc::channel(128);
+        // ends once the watcher is dropped and its sender with it
         let wrapper_handle = tokio::task::spawn_blocking(move || {
-            loop {
-                if let Ok(event) = channel.recv() {
-                    debug!("std->tokio wrapper received event: {:?}", event);
-                    let inner_tx = tx.clone();
-                    tokio::spawn(async move {
-                        inner_tx.send(event).await.ok();
-                    });
-                }
+            while let Ok(event) = channel.recv() {
+                debug!("std->tokio wrapper received event: {:?}", event);
+                let inner_tx = tx.clone();
+                tokio::spawn(async move {
+                    inner_tx.send(event).await.ok();
+                });
             }
         });
 
-        let handle = tokio::spawn(async move {
-            let watcher = std::mem::ManuallyDrop::new(watcher);
+        // runs in the watcher task, so stopping that task drops the watcher
+        let receiver = async move {
+            let mut watcher = watcher;
+            let mut missing_check = tokio::time::interval(std::time::Duration::from_secs(MISSING_FOLDER_CHECK_SECONDS));
             let mut rcv_errs = 0u8;
             loop {
-                if let Some(event) = rx.recv().await {
+                let event = tokio::select! {
+                    event = rx.recv() => event,
+                    _ = missing_check.tick(), if !missing.is_empty() => {
+                        // maps which were added before the folder was watched are only found by indexing
+                        if watch_appeared_folders(&mut watcher, &mut missing) {
+                            config.clone().update_map_index(false).await;
+                        }
+                        continue;
+                    }
+                };
+                if let Some(event) = event {
                     debug!("Received fs event: {:?}", event);
                     rcv_errs = 0;
+                    crate::metrics::record_watcher_event(&config.config.id, event_kind(&event));
                     match event {
                         DebouncedEvent::Create(path) => {
                             debug!("Created: {}", path.display());
@@ -141,8 +195,10 @@ impl PcMapsWatcher {
                         DebouncedEvent::Error(error, path) => {
                             if let Some(path) = path {
                                 error!("An error occurred at file {}: {}", path.display(), error);
+                                crate::status::record_error(&config.config.id, format!("{}: {}", path.display(), error));
                             } else {
                                 error!("An error occurred: {}", error);
+                                crate::status::record_error(&config.config.id, error.to_string());
                             }
                         }
                         _ => {}
@@ -155,13 +211,12 @@ impl PcMapsWatcher {
                     }
                 }
             }
-            std::mem::drop(watcher);
-        });
+        };
         tokio::select! {
             _val = wrapper_handle => {
                 warn!("Wrapper handle died!");
             }
-            _val = handle => {
+            _val = receiver => {
                 warn!("FSEvent handle died!");
             }
         }
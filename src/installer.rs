@@ -1,10 +1,10 @@
 use crate::websocket_handler::{ConfigData, InstallType};
 use log::{debug, info, warn, error};
-use std::io::{Cursor, Read, Seek};
+use std::io::{Read, Seek};
 use zip::ZipArchive;
 use zip::result::ZipError;
 use std::{fs, io, env};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use crate::installer::Installer::{PC, Quest};
 use crate::beatsaver::{BeatSaverMap, MapVersion, BeatSaverError, BeatSaverDownloadError};
 use tokio::task::JoinHandle;
@@ -13,6 +13,13 @@ use std::process::Command;
 use thiserror::Error;
 use std::time::Duration;
 use crate::installer::InstallRequestError::HttpError;
+use crate::backup::{BackupResult, RestoreRequest, RestoreResult};
+use crate::repair::RepairReport;
+use uuid::Uuid;
+use crate::daemon_settings::DaemonSettings;
+use reqwest::Method;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 
 #[derive(Clone)]
 pub enum Installer {
@@ -60,9 +67,11 @@ pub async fn push_map_to_install_queues(hash: String) -> Result<(), InstallReque
         .connect_timeout(Duration::from_secs(5))
         .timeout(Duration::from_secs(5))
         .build().unwrap();
-    let mut uri = "http://localhost:2706/queue/map/".to_owned();
+    let mut uri = DaemonSettings::read_from_file().local_url();
+    uri.push_str("/queue/map/");
     uri.push_str(hash.as_str());
     let install_request = client.post(uri)
+        .bearer_auth(crate::auth::local_token().unwrap_or_default())
         .send().await;
     match install_request {
         Ok(response) => {
@@ -78,8 +87,65 @@ pub async fn push_map_to_install_queues(hash: String) -> Result<(), InstallReque
     }
 }
 
+/// Sends a request with the local token to the running daemon, statuses other than success are errors
+async fn send_to_daemon<B: Serialize>(method: Method, path: &str, body: Option<&B>, timeout: Duration) -> Result<reqwest::Response, InstallRequestError> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(timeout)
+        .build().unwrap();
+    let uri = format!("{}{}", DaemonSettings::read_from_file().local_url(), path);
+    let mut request = client.request(method, uri)
+        .bearer_auth(crate::auth::local_token().unwrap_or_default());
+    if let Some(body) = body {
+        request = request.json(body);
+    }
+    let response = request.send().await.map_err(HttpError)?;
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(InstallRequestError::HttpStatusError(response.status().as_u16()))
+    }
+}
+
+/// Calls an endpoint of the running daemon and parses its json response
+async fn daemon_request<T: DeserializeOwned, B: Serialize>(method: Method, path: &str, body: Option<&B>, timeout: Duration) -> Result<T, InstallRequestError> {
+    send_to_daemon(method, path, body, timeout).await?
+        .json().await
+        .map_err(HttpError)
+}
+
+/// Asks the running daemon to repair the invalid maps of an installation, as only the daemon owns the map index
+pub async fn push_repair_to_daemon(installation: Uuid) -> Result<RepairReport, InstallRequestError> {
+    daemon_request(Method::POST, format!("/installations/{}/repair", installation).as_str(), None::<&()>,
+                   Duration::from_secs(600)).await
+}
+
+/// Asks the running daemon to write a backup, as only the daemon owns the map index
+pub async fn push_backup_to_daemon(installation: Uuid, archive: bool) -> Result<BackupResult, InstallRequestError> {
+    daemon_request(Method::POST, format!("/installations/{}/backup?archive={}", installation, archive).as_str(), None::<&()>,
+                   Duration::from_secs(3600)).await
+}
+
+/// Asks the running daemon to restore a backup, as only the daemon owns the download queue
+pub async fn push_restore_to_daemon(installation: Uuid, request: &RestoreRequest) -> Result<RestoreResult, InstallRequestError> {
+    daemon_request(Method::POST, format!("/installations/{}/restore", installation).as_str(), Some(request),
+                   Duration::from_secs(600)).await
+}
+
+/// Confirms a pairing request of a web client with the code it displays
+pub async fn push_pairing_confirmation(code: &str) -> Result<String, InstallRequestError> {
+    send_to_daemon(Method::POST, format!("/pair/confirm/{}", code).as_str(), None::<&()>, Duration::from_secs(5)).await?
+        .text().await
+        .map_err(HttpError)
+}
+
+/// Asks the running daemon for `GET /status`
+pub async fn fetch_daemon_status() -> Result<serde_json::Value, InstallRequestError> {
+    daemon_request(Method::GET, "/status", None::<&()>, Duration::from_secs(5)).await
+}
+
 impl PcInstaller {
-    pub fn install_map(&self, map: BeatSaverMap, data: &[u8]) {
+    pub fn install_map(&self, map: BeatSaverMap, data: &Path) {
         let mut full_name = map.id.clone();
         full_name.push_str(" (");
         full_name.push_str(map.metadata.song_name.as_str());
@@ -87,10 +153,7 @@ impl PcInstaller {
         full_name.push_str(map.metadata.level_author_name.as_str());
         full_name.push_str(")");
 
-        let target = self.config.install_location.clone();
-        let mut target = PathBuf::from(target);
-        target.push("Beat Saber_Data");
-        target.push("CustomLevels");
+        let mut target = crate::song_core::default_map_folder(&self.config);
         target.push(full_name
             .replace("\\", "")
             .replace("/", "")
@@ -109,7 +172,7 @@ impl PcInstaller {
 
 impl QuestInstaller {
     // todo: error types
-    pub fn install_map(&self, version: MapVersion, data: Vec<u8>) -> Result<Option<JoinHandle<Result<(), String>>>, String> {
+    pub fn install_map(&self, version: MapVersion, data: &Path) -> Result<Option<JoinHandle<Result<(), String>>>, String> {
         let mut full_name = "custom_level_".to_owned();
         full_name.push_str(version.clone().hash.as_str());
 
@@ -120,7 +183,7 @@ impl QuestInstaller {
             let mut tmp_dir = unpack_dir.clone();
             tmp_dir.push(full_name.clone());
 
-            if let Ok(archive) = as_zip_archive(data.as_ref()) {
+            if let Ok(archive) = as_zip_archive(data) {
                 unzip_to(archive, tmp_dir.clone());
             }
 
@@ -186,7 +249,7 @@ impl QuestInstaller {
             bmbf_referer.push_str("/main/upload");
             let mut referer_header = "Referer: ".to_owned();
             referer_header.push_str(bmbf_referer.as_str());
-            let data = data.clone();
+            let data = data.to_path_buf();
             Ok(Some(tokio::spawn(async move {
                 let mut curl = curl::easy::Easy::new();
                 curl.url(bmbf_host.as_str()).unwrap();
@@ -197,7 +260,8 @@ impl QuestInstaller {
                 curl.http_headers(headers).unwrap();
                 let mut form = Form::new();
                 form.part("file")
-                    .buffer(full_name.as_str(), data)
+                    .file(&data)
+                    .filename(full_name.as_str())
                     .add()
                     .unwrap();
                 curl.httppost(form).unwrap();
@@ -222,6 +286,16 @@ impl QuestInstaller {
     }
 }
 
+/// Size of all files of a map archive once it is extracted
+pub fn unpacked_size(data: &Path) -> u64 {
+    match as_zip_archive(data) {
+        Ok(mut archive) => (0..archive.len())
+            .filter_map(|i| archive.by_index(i).ok().map(|file| file.size()))
+            .sum(),
+        Err(_) => 0
+    }
+}
+
 fn unzip_to<R: Read + Seek>(mut archive: ZipArchive<R>, target: PathBuf) {
     fs::create_dir_all(&target).ok();
     for i in 0..archive.len() {
@@ -262,9 +336,15 @@ fn unzip_to<R: Read + Seek>(mut archive: ZipArchive<R>, target: PathBuf) {
     }
 }
 
-fn as_zip_archive(bytes: &[u8]) -> Result<ZipArchive<Cursor<&[u8]>>, ()> {
-    let buf = Cursor::new(bytes);
-    let archive = zip::ZipArchive::new(buf);
+fn as_zip_archive(path: &Path) -> Result<ZipArchive<fs::File>, ()> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(error) => {
+            error!("Cannot open zip {}: {}", path.display(), error);
+            return Err(());
+        }
+    };
+    let archive = zip::ZipArchive::new(file);
     match archive {
         Ok(archive) => {
             return Ok(archive);
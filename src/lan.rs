@@ -0,0 +1,212 @@
+use crate::daemon_settings::LanSettings;
+use futures_util::{Stream, StreamExt};
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use native_tls::Identity;
+use std::convert::Infallible;
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Duration;
+use tokio_native_tls::{TlsAcceptor, TlsStream};
+use log::{debug, info, warn};
+use thiserror::Error;
+use uuid::Uuid;
+use warp::Filter;
+
+const GENERATED_CERTIFICATE_FILE: &str = "lan-certificate.p12";
+/// Random password of the generated certificate, only readable by the daemon user like the certificate
+const GENERATED_PASSWORD_FILE: &str = "lan-certificate.password";
+const CERTIFICATE_NAME: &str = "aiosaber.local";
+const HANDSHAKE_TIMEOUT_SECONDS: u64 = 10;
+#[cfg(not(target_family = "windows"))]
+const PASSWORD_VARIABLE: &str = "AIOSABER_CERTIFICATE_PASSWORD";
+
+#[derive(Error, Debug)]
+pub enum LanError {
+    #[error("Cannot read certificate {0}: {1}")]
+    Certificate(PathBuf, std::io::Error),
+    #[error("Cannot generate certificate: {0}")]
+    Generation(String),
+    #[error("Invalid certificate: {0}")]
+    Tls(#[from] native_tls::Error),
+    #[error("Cannot listen on LAN address: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+fn file_path(name: &str) -> PathBuf {
+    let mut path = env::current_dir().unwrap();
+    path.push(name);
+    path
+}
+
+/// Writes a file which only the current user may read, it is created with these permissions
+/// so the content is never visible to others, not even for a moment
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // An existing file keeps its permissions on open
+        if path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(content)
+}
+
+/// Loads the configured certificate or the self-signed one, which is generated on first use
+fn load_identity(settings: &LanSettings) -> Result<Identity, LanError> {
+    let (path, password) = match &settings.certificate {
+        Some(path) => (path.clone(), settings.certificate_password.clone()),
+        None => {
+            let path = file_path(GENERATED_CERTIFICATE_FILE);
+            let password_path = file_path(GENERATED_PASSWORD_FILE);
+            // Certificates without a password file were generated with a fixed password, replace them
+            if !path.exists() || !password_path.exists() {
+                let password = Uuid::new_v4().to_simple().to_string();
+                generate_certificate(path.as_path(), password.as_str())?;
+                write_private(password_path.as_path(), password.as_bytes())
+                    .map_err(|err| LanError::Certificate(password_path.clone(), err))?;
+            }
+            let password = std::fs::read_to_string(&password_path)
+                .map_err(|err| LanError::Certificate(password_path.clone(), err))?;
+            (path, password.trim().to_string())
+        }
+    };
+    let der = std::fs::read(&path).map_err(|err| LanError::Certificate(path.clone(), err))?;
+    Ok(Identity::from_pkcs12(der.as_ref(), password.as_str())?)
+}
+
+#[cfg(not(target_family = "windows"))]
+fn generate_certificate(path: &Path, password: &str) -> Result<(), LanError> {
+    let key = path.with_extension("key.pem");
+    let certificate = path.with_extension("pem");
+    // openssl keeps the permissions of existing files, so the key and the export are never readable by others
+    for private in [&key, &path.to_path_buf()].iter() {
+        write_private(private, &[]).map_err(|err| LanError::Certificate(private.to_path_buf(), err))?;
+    }
+    let run = |args: Vec<&std::ffi::OsStr>| -> Result<(), LanError> {
+        let output = std::process::Command::new("openssl")
+            .args(args)
+            .env(PASSWORD_VARIABLE, password)
+            .output()
+            .map_err(|err| LanError::Generation(format!("openssl is not available: {}", err)))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(LanError::Generation(String::from_utf8_lossy(output.stderr.as_ref()).trim().to_string()))
+        }
+    };
+    let subject = format!("/CN={}", CERTIFICATE_NAME);
+    run(vec![
+        "req".as_ref(), "-x509".as_ref(), "-newkey".as_ref(), "rsa:2048".as_ref(), "-nodes".as_ref(),
+        "-days".as_ref(), "3650".as_ref(), "-subj".as_ref(), subject.as_ref(),
+        "-keyout".as_ref(), key.as_os_str(), "-out".as_ref(), certificate.as_os_str(),
+    ])?;
+    // Passed in the environment, arguments are visible to every user
+    let password_source = format!("env:{}", PASSWORD_VARIABLE);
+    let exported = run(vec![
+        "pkcs12".as_ref(), "-export".as_ref(), "-inkey".as_ref(), key.as_os_str(),
+        "-in".as_ref(), certificate.as_os_str(), "-out".as_ref(), path.as_os_str(),
+        "-passout".as_ref(), password_source.as_ref(),
+    ]);
+    // The key is only kept inside the PKCS#12 file, the certificate stays for clients to trust it
+    if let Err(err) = std::fs::remove_file(&key) {
+        warn!("Cannot remove {}: {}", key.display(), err);
+    }
+    exported?;
+    info!("Generated self-signed certificate {}", certificate.display());
+    Ok(())
+}
+
+#[cfg(target_family = "windows")]
+fn generate_certificate(path: &Path, password: &str) -> Result<(), LanError> {
+    let command = format!("$cert = New-SelfSignedCertificate -DnsName '{}' -CertStoreLocation 'Cert:\\CurrentUser\\My' -NotAfter (Get-Date).AddYears(10)
+$password = ConvertTo-SecureString -String '{}' -Force -AsPlainText
+Export-PfxCertificate -Cert $cert -FilePath '{}' -Password $password
+Export-Certificate -Cert $cert -FilePath '{}'",
+                          CERTIFICATE_NAME,
+                          password,
+                          path.display().to_string().replace('\'', "''"),
+                          path.with_extension("cer").display().to_string().replace('\'', "''"));
+    powershell_script::run(command.as_str(), false)
+        .map_err(|err| LanError::Generation(err.to_string()))?;
+    info!("Generated self-signed certificate {}", path.display());
+    Ok(())
+}
+
+/// Accepts TLS connections on the LAN address, handshakes happen in their own tasks
+/// so a slow client cannot block others
+pub async fn tls_incoming(settings: &LanSettings) -> Result<impl Stream<Item=(TlsStream<TcpStream>, SocketAddr)>, LanError> {
+    let identity = load_identity(settings)?;
+    let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
+    let listener = TcpListener::bind(settings.socket_addr()).await?;
+    info!("Listening for LAN clients on https://{}", settings.socket_addr());
+    let (tx, rx) = tokio::sync::mpsc::channel(32);
+    tokio::spawn(async move {
+        loop {
+            // the web server dropped the stream, free the port
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = tx.closed() => break
+            };
+            let (stream, peer) = match accepted {
+                Ok(connection) => connection,
+                Err(err) => {
+                    warn!("Cannot accept LAN connection: {}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS), acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send((stream, peer)).await;
+                    }
+                    Ok(Err(err)) => debug!("TLS handshake with {} failed: {}", peer, err),
+                    Err(_) => debug!("TLS handshake with {} timed out", peer)
+                }
+            });
+        }
+    });
+    Ok(futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|connection| (connection, rx))
+    }))
+}
+
+/// Peer of a LAN connection, warp only knows the peers of the listeners it binds itself
+#[derive(Clone, Copy)]
+struct LanPeer(SocketAddr);
+
+/// Address of the client on both the local and the LAN listener
+pub fn remote_addr() -> impl Filter<Extract=(Option<SocketAddr>, ), Error=Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<LanPeer>())
+        .map(|remote: Option<SocketAddr>, peer: Option<LanPeer>| remote.or_else(|| peer.map(|peer| peer.0)))
+}
+
+/// Serves the accepted LAN connections, their requests carry the peer address for `remote_addr`
+pub async fn serve<S>(incoming: impl Stream<Item=(TlsStream<TcpStream>, SocketAddr)>, service: S)
+    where S: Service<Request<Body>, Response=Response<Body>, Error=Infallible> + Clone + Send + 'static,
+          S::Future: Send + 'static {
+    futures_util::pin_mut!(incoming);
+    while let Some((stream, peer)) = incoming.next().await {
+        let service = service.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |mut request: Request<Body>| {
+                request.extensions_mut().insert(LanPeer(peer));
+                service.clone().call(request)
+            });
+            if let Err(err) = hyper::server::conn::Http::new().serve_connection(stream, service).with_upgrades().await {
+                debug!("LAN connection with {} failed: {}", peer, err);
+            }
+        });
+    }
+}
@@ -0,0 +1,118 @@
+use chrono::{DateTime, Utc};
+use env_logger::Env;
+use lazy_static::lazy_static;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Records kept for `GET /logs`
+const BUFFER_SIZE: usize = 2000;
+/// Target of the records logged while sending WebSocket messages. They aren't published to the
+/// `Logs` topic, as sending them would log another record.
+pub const WEBSOCKET_SEND_TARGET: &str = "aiosaber_client::websocket_send";
+
+lazy_static! {
+    static ref BUFFER: Mutex<VecDeque<LogRecord>> = Mutex::new(VecDeque::with_capacity(BUFFER_SIZE));
+    static ref LOG_EVENTS: tokio::sync::broadcast::Sender<LogRecord> = tokio::sync::broadcast::channel(256).0;
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogRecord {
+    pub time: DateTime<Utc>,
+    pub level: String,
+    pub module: String,
+    pub message: String,
+}
+
+/// Filters of `GET /logs`
+#[derive(Clone, Default, Deserialize)]
+pub struct LogFilter {
+    /// Least severe level to include, e.g. `warn` also returns errors
+    pub level: Option<String>,
+    /// Prefix of the module path, e.g. `aiosaber_client::queue_handler`
+    pub module: Option<String>,
+    /// Only the newest records
+    pub limit: Option<usize>,
+}
+
+/// Writes to the console like env_logger and keeps recent records for the configurator
+struct BufferedLogger {
+    console: env_logger::Logger,
+}
+
+impl Log for BufferedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info || self.console.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.console.log(record);
+        let record = LogRecord {
+            time: Utc::now(),
+            level: record.level().to_string(),
+            module: record.target().to_string(),
+            message: record.args().to_string(),
+        };
+        if let Ok(mut buffer) = BUFFER.lock() {
+            if buffer.len() >= BUFFER_SIZE {
+                buffer.pop_front();
+            }
+            buffer.push_back(record.clone());
+        }
+        if record.module.ne(WEBSOCKET_SEND_TARGET) {
+            LOG_EVENTS.send(record).ok();
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+    }
+}
+
+/// Replaces `env_logger::init_from_env`, info and above are always buffered
+pub fn init(env: Env) {
+    let console = env_logger::Builder::from_env(env).build();
+    let max_level = console.filter().max(LevelFilter::Info);
+    log::set_boxed_logger(Box::new(BufferedLogger { console }))
+        .expect("Logger was already initialized");
+    log::set_max_level(max_level);
+}
+
+pub fn subscribe_log_events() -> tokio::sync::broadcast::Receiver<LogRecord> {
+    LOG_EVENTS.subscribe()
+}
+
+impl LogFilter {
+    fn matches(&self, record: &LogRecord, level: Option<Level>) -> bool {
+        if let Some(level) = level {
+            match Level::from_str(record.level.as_str()) {
+                Ok(record_level) if record_level <= level => {}
+                _ => return false
+            }
+        }
+        match &self.module {
+            Some(module) => record.module.starts_with(module.as_str()),
+            None => true
+        }
+    }
+
+    pub fn records(&self) -> Vec<LogRecord> {
+        let level = self.level.as_ref().and_then(|level| Level::from_str(level.as_str()).ok());
+        let buffer = BUFFER.lock().unwrap();
+        let mut records: Vec<LogRecord> = buffer.iter()
+            .filter(|record| self.matches(record, level))
+            .cloned()
+            .collect();
+        drop(buffer);
+        if let Some(limit) = self.limit {
+            records.drain(..records.len().saturating_sub(limit));
+        }
+        records
+    }
+}
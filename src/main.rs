@@ -7,12 +7,32 @@ mod installer;
 mod map_index;
 mod queue_handler;
 mod file_watcher;
+mod song_core;
+mod map_query;
+mod playlists;
+mod duplicates;
+mod repair;
+mod unknown_resolver;
+mod backup;
+mod sync;
+mod routing;
+mod quota;
+mod auth;
+mod audit_log;
+mod daemon_settings;
+mod lan;
+mod mdns;
+mod sessions;
+mod log_buffer;
+mod supervisor;
+mod status;
+mod metrics;
+mod config_watcher;
 
 #[cfg(not(target_family = "windows"))]
 use jemallocator::Jemalloc;
 use env_logger::Env;
 use crate::webserver::WebServer;
-use std::net::{SocketAddr, IpAddr};
 use std::str::FromStr;
 use log::{info, warn, error};
 use std::process::exit;
@@ -21,14 +41,20 @@ use crate::config::DaemonConfig;
 use curl::easy::Easy;
 use std::path::PathBuf;
 use crate::queue_handler::DownloadQueueHandler;
+use crate::unknown_resolver::UnknownResolver;
+use crate::sync::MirrorHandler;
+use crate::config_watcher::ConfigWatcher;
 
 #[cfg(not(target_family = "windows"))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+/// Set for daemon processes started by `/admin/restart`
+pub const RESTARTED_ENV: &str = "AIOSABER_RESTARTED";
+
 #[tokio::main]
 async fn main() {
-    env_logger::init_from_env(Env::new().default_filter_or("info"));
+    log_buffer::init(Env::new().default_filter_or("info"));
 
     env::set_current_dir(env::current_exe().unwrap().parent().unwrap()).ok();
 
@@ -62,17 +88,21 @@ async fn main() {
         }
 
         if operator.eq("--scan-maps") {
-            if env::args().len() != 4 {
-                error!("--scan-maps <--aggressive/--relaxed> <path>");
+            if env::args().len() != 4 && env::args().len() != 5 {
+                error!("--scan-maps <--aggressive/--relaxed> <path> [--bench]");
             } else {
                 let aggressive = env::args().nth(2).unwrap().eq("--aggressive");
                 let dir = env::args().nth(3).unwrap();
                 let path = std::path::PathBuf::from_str(dir.as_str())
                     .expect("Path is not a dir");
-                let start = std::time::SystemTime::now();
+                let bench = env::args().nth(4).map(|arg| arg.eq("--bench")).unwrap_or(false);
+                let start = std::time::Instant::now();
                 let results = map_index::index_maps(path, aggressive).await.expect("Cannot index");
-                let duration = start.elapsed().unwrap().as_millis();
-                info!("Indexing took: {}ms", duration);
+                let elapsed = start.elapsed();
+                info!("Indexing took: {}ms", elapsed.as_millis());
+                if bench {
+                    report_throughput(&results, elapsed);
+                }
                 let size = results.len();
                 let data = results.iter()
                     .filter_map(|result| result.as_ref().ok())
@@ -110,6 +140,90 @@ async fn main() {
             return;
         }
 
+        if operator.eq("--repair-maps") {
+            let installations = match env::args().nth(2) {
+                Some(id) => vec![uuid::Uuid::from_str(id.as_str()).expect("Invalid installation id")],
+                None => match installer::fetch_daemon_status().await {
+                    Ok(status) => status["installations"].as_array().cloned().unwrap_or_default().iter()
+                        .filter(|installation| installation["installType"].as_str().eq(&Some("PC")))
+                        .filter_map(|installation| installation["id"].as_str()
+                            .and_then(|id| uuid::Uuid::from_str(id).ok()))
+                        .collect(),
+                    Err(err) => {
+                        error!("Cannot reach the daemon: {:?}", err);
+                        Vec::new()
+                    }
+                }
+            };
+            for id in installations {
+                info!("Repairing maps of {}", id);
+                match installer::push_repair_to_daemon(id).await {
+                    Ok(report) => for entry in report.entries {
+                        info!("{}: {}", entry.path.display(), serde_json::to_string(&entry.action).unwrap());
+                    },
+                    Err(err) => error!("Failure: {:?}", err)
+                }
+            }
+            return;
+        }
+
+        if operator.eq("--backup") {
+            if env::args().len() != 3 && env::args().len() != 4 {
+                error!("--backup <installation-id> [--archive]");
+            } else {
+                let id = uuid::Uuid::from_str(env::args().nth(2).unwrap().as_str()).expect("Invalid installation id");
+                let archive = env::args().nth(3).map(|arg| arg.eq("--archive")).unwrap_or(false);
+                info!("Sending backup request to the daemon...");
+                match installer::push_backup_to_daemon(id, archive).await {
+                    Ok(result) => info!("Backup of {} maps, {} playlists and {} mods written to {}",
+                                        result.maps, result.playlists, result.mods, result.path.display()),
+                    Err(err) => error!("Failure: {:?}", err)
+                }
+            }
+            return;
+        }
+
+        if operator.eq("--restore") {
+            if env::args().len() != 4 && env::args().len() != 5 {
+                error!("--restore <installation-id> <backup-path> [--from-archive]");
+            } else {
+                let id = uuid::Uuid::from_str(env::args().nth(2).unwrap().as_str()).expect("Invalid installation id");
+                let path = std::fs::canonicalize(env::args().nth(3).unwrap()).expect("Backup not found");
+                let request = backup::RestoreRequest {
+                    path,
+                    from_archive: env::args().nth(4).map(|arg| arg.eq("--from-archive")).unwrap_or(false),
+                };
+                info!("Sending restore request to the daemon...");
+                match installer::push_restore_to_daemon(id, &request).await {
+                    Ok(result) => info!("Extracted {} maps, queued {} maps, {} already installed, {} not found on BeatSaver",
+                                        result.extracted, result.queued.len(), result.already_installed, result.unresolvable.len()),
+                    Err(err) => error!("Failure: {:?}", err)
+                }
+            }
+            return;
+        }
+
+        if operator.eq("--confirm-pairing") {
+            if env::args().len() != 3 {
+                error!("--confirm-pairing takes exactly one extra argument");
+            } else {
+                let code = env::args().nth(2).unwrap();
+                match installer::push_pairing_confirmation(code.as_str()).await {
+                    Ok(client_name) => info!("Paired {}", client_name),
+                    Err(err) => error!("Failure: {:?}", err)
+                }
+            }
+            return;
+        }
+
+        if operator.eq("--status") {
+            match installer::fetch_daemon_status().await {
+                Ok(status) => print_status(&status),
+                Err(err) => error!("Cannot reach the daemon: {:?}", err)
+            }
+            return;
+        }
+
         if operator.eq("--test-adb") {
             match installer::execute_adb("adb".to_owned(), vec!["version"]) {
                 Ok(_) => info!("ADB found & successfully executed"),
@@ -183,30 +297,109 @@ async fn main() {
 
     let version = env!("CLIENT_VERSION").to_string();
 
+    if env::var(RESTARTED_ENV).is_ok() {
+        // give the previous process time to release the port
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    }
+
     let (queue_handler_tx, queue_handler_rx) = tokio::sync::mpsc::channel(1024);
-    let config = DaemonConfig::new(queue_handler_tx);
+    let mut config = DaemonConfig::new(queue_handler_tx);
+    config.settings.apply_args(env::args().skip(1).collect());
+    let addr = config.settings.socket_addr();
+    info!("Listening on {}", addr);
     let (web_server, socket_handler) = WebServer::create_server(version, config.clone())
-        .start(SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), 2706));
-    let websocket_sender = socket_handler.get_sender();
-    let websocket_handle = socket_handler.start();
-    let queue_handle = DownloadQueueHandler::new(queue_handler_rx, config, websocket_sender).start();
+        .start(addr);
+    let websocket_sessions = socket_handler.get_sessions();
+    let websocket_handle = supervisor::supervise("websocket-handler".to_string(), None,
+                                                 move || Ok(socket_handler.clone().start()));
+    let resolver_config = config.clone();
+    let resolver_handle = supervisor::supervise("unknown-resolver".to_string(), None,
+                                                move || Ok(UnknownResolver::new(resolver_config.clone()).start()));
+    let mirror_config = config.clone();
+    let mirror_handle = supervisor::supervise("mirror-handler".to_string(), None,
+                                              move || Ok(MirrorHandler::new(mirror_config.clone()).start()));
+    let watcher_config = config.clone();
+    let config_watcher_handle = supervisor::supervise("config-watcher".to_string(), None, move || {
+        ConfigWatcher::new(watcher_config.clone()).start()
+            .map_err(|err| format!("Failed to watch daemon-config.yaml: {}", err))
+    });
+    let queue_handler = DownloadQueueHandler::new(queue_handler_rx, config, websocket_sessions);
+    let queue_handle = supervisor::supervise("download-queue".to_string(), None,
+                                             move || Ok(queue_handler.clone().start()));
 
+    // supervised tasks restart themselves, their handles only complete if they get stopped
     tokio::select! {
         _val = web_server => {
-            warn!("Webserver died. Restarting!");
+            warn!("Webserver stopped. Restarting!");
             exit(1);
         }
         _val = websocket_handle => {
-            warn!("WebSocket Handler died. Restarting!");
+            warn!("WebSocket Handler stopped. Restarting!");
             exit(1);
         }
         _val = queue_handle => {
-            warn!("Download Queue Handler died. Restarting!");
+            warn!("Download Queue Handler stopped. Restarting!");
+            exit(1);
+        }
+        _val = resolver_handle => {
+            warn!("Unknown Map Resolver stopped. Restarting!");
+            exit(1);
+        }
+        _val = mirror_handle => {
+            warn!("Mirror Handler stopped. Restarting!");
+            exit(1);
+        }
+        _val = config_watcher_handle => {
+            warn!("Config Watcher stopped. Restarting!");
             exit(1);
         }
     }
 }
 
+fn print_status(status: &serde_json::Value) {
+    let healthy = |value: &serde_json::Value| if value["healthy"].as_bool().unwrap_or(false) { "healthy" } else { "UNHEALTHY" };
+    let task = |value: &serde_json::Value| format!("{} ({} restarts{})",
+                                                   value["state"].as_str().unwrap_or("missing"),
+                                                   value["restarts"].as_u64().unwrap_or(0),
+                                                   value["lastError"].as_str().map(|err| format!(", last error: {}", err)).unwrap_or_default());
+    info!("Daemon {}: {}", status["version"].as_str().unwrap_or_default(), healthy(status));
+    for value in status["tasks"].as_array().cloned().unwrap_or_default() {
+        info!("  {}: {}", value["name"].as_str().unwrap_or_default(), task(&value));
+    }
+    for err in status["configErrors"].as_array().cloned().unwrap_or_default() {
+        error!("daemon-config.yaml: {}", err.as_str().unwrap_or_default());
+    }
+    for installation in status["installations"].as_array().cloned().unwrap_or_default() {
+        info!("Installation {} ({}, {}): {}", installation["id"].as_str().unwrap_or_default(),
+              installation["installType"].as_str().unwrap_or_default(),
+              installation["installLocation"].as_str().unwrap_or_default(), healthy(&installation));
+        if !installation["watcher"].is_null() {
+            info!("  watcher: {}", task(&installation["watcher"]));
+        }
+        info!("  installer queue: {}, {} waiting", task(&installation["installerQueue"]),
+              installation["queueDepth"].as_u64().unwrap_or(0));
+        info!("  {} maps, last index: {}", installation["maps"].as_u64().unwrap_or(0),
+              installation["lastIndex"].as_str().unwrap_or("never"));
+        if let Some(message) = installation["lastError"]["message"].as_str() {
+            info!("  last error at {}: {}", installation["lastError"]["time"].as_str().unwrap_or_default(), message);
+        }
+    }
+}
+
+/// Throughput of an indexing pass, the hashed bytes are summed up from the file sizes afterwards
+fn report_throughput(results: &[Result<(PathBuf, String), map_index::IndexError>], elapsed: std::time::Duration) {
+    let mut maps = 0u64;
+    let mut bytes = 0u64;
+    for (path, _) in results.iter().filter_map(|result| result.as_ref().ok()) {
+        maps += 1;
+        bytes += map_index::hashed_size(path.as_path()).unwrap_or(0);
+    }
+    let seconds = elapsed.as_secs_f64();
+    info!("Hashed {} maps ({:.2} MiB) in {:.2}s: {:.1} maps/s, {:.2} MiB/s",
+          maps, bytes as f64 / 1048576f64, seconds,
+          maps as f64 / seconds, bytes as f64 / 1048576f64 / seconds);
+}
+
 pub mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
\ No newline at end of file
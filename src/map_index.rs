@@ -1,10 +1,11 @@
 use thiserror::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::Read;
 use std::option::Option::Some;
 use tokio::task::JoinError;
 use futures_util::stream::StreamExt;
 use log::debug;
+use serde::{Serialize, Deserialize};
 
 #[derive(Error, Debug)]
 pub enum IndexError {
@@ -30,70 +31,68 @@ enum MaybeJoinHandle<T, H> {
 }
 
 pub async fn index_maps(path: PathBuf, aggressive: bool) -> Result<Vec<Result<(PathBuf, String), IndexError>>, IndexError> {
+    let mut paths = Vec::new();
+    let mut errors = Vec::new();
+    for entry in list_map_directories(path).await? {
+        match entry {
+            Ok(path) => paths.push(path),
+            Err(err) => errors.push(Err(err))
+        }
+    }
+    let mut vec = index_map_directories(paths, aggressive).await;
+    vec.append(&mut errors);
+    Ok(vec)
+}
+
+/// Lists all entries of a maps directory, without hashing them
+pub async fn list_map_directories(path: PathBuf) -> Result<Vec<Result<PathBuf, IndexError>>, IndexError> {
     let inner_path = path.clone();
     let read_result = tokio::task::spawn_blocking(move || {
-        match std::fs::read_dir(inner_path) {
-            Ok(dir) => {
-                Ok(dir)
-            }
-            Err(err) => {
-                Err(err)
-            }
-        }
+        std::fs::read_dir(inner_path)
+            .map(|dir| dir
+                .map(|entry| entry
+                    .map(|entry| entry.path())
+                    .map_err(IndexError::CannotReadMap))
+                .collect::<Vec<Result<PathBuf, IndexError>>>())
     }).await;
 
     match read_result {
-        Ok(result) => {
-            match result {
-                Ok(dir) => {
-                    let mut vec = Vec::new();
-                    for entry in dir {
-                        match entry {
-                            Ok(entry) => {
-                                if aggressive {
-                                    let handle = tokio::spawn(async move {
-                                        process_map_directory(entry).await
-                                    });
-                                    vec.push(MaybeJoinHandle::Handle(handle));
-                                } else {
-                                    vec.push(MaybeJoinHandle::Raw(process_map_directory(entry).await))
-                                }
-                            }
-                            Err(err) => {
-                                vec.push(MaybeJoinHandle::Raw(Err(IndexError::CannotReadMap(err))))
-                            }
-                        }
+        Ok(result) => result.map_err(IndexError::CannotReadMapsDir),
+        Err(err) => Err(IndexError::JoinError(err, path))
+    }
+}
+
+/// Hashes the given map directories, concurrently if aggressive. The results are in the order of the directories
+pub async fn index_map_directories(paths: Vec<PathBuf>, aggressive: bool) -> Vec<Result<(PathBuf, String), IndexError>> {
+    let mut vec = Vec::new();
+    for path in paths {
+        if aggressive {
+            let inner_path = path.clone();
+            let handle = tokio::spawn(async move {
+                process_map_directory(inner_path).await
+            });
+            vec.push((MaybeJoinHandle::Handle(handle), path));
+        } else {
+            vec.push((MaybeJoinHandle::Raw(process_map_directory(path.clone()).await), path))
+        }
+    }
+    futures_util::stream::iter(vec)
+        .then(|(handle, path)| async move {
+            match handle {
+                MaybeJoinHandle::Handle(task) => {
+                    match task.await {
+                        Ok(result) => result,
+                        Err(err) => Err(IndexError::JoinError(err, path))
                     }
-                    let vec = futures_util::stream::iter(vec.into_iter())
-                        .map(|handle| (handle, path.clone()))
-                        .then(|(handle, path) | async move {
-                            match handle {
-                                MaybeJoinHandle::Handle(task) => {
-                                    match task.await {
-                                        Ok(result) => result,
-                                        Err(err) => Err(IndexError::JoinError(err, path))
-                                    }
-                                }
-                                MaybeJoinHandle::Raw(result) => result
-                            }
-                        })
-                        .collect::<Vec<Result<(PathBuf, String), IndexError>>>().await;
-                    Ok(vec)
-                }
-                Err(err) => {
-                    Err(IndexError::CannotReadMapsDir(err))
                 }
+                MaybeJoinHandle::Raw(result) => result
             }
-        }
-        Err(err) => {
-            Err(IndexError::JoinError(err, path.clone()))
-        }
-    }
+        })
+        .collect::<Vec<Result<(PathBuf, String), IndexError>>>().await
 }
 
-pub async fn process_map_directory(entry: std::fs::DirEntry) -> Result<(PathBuf, String), IndexError> {
-    let buf = entry.path();
-    debug!("Processing {}", buf.as_path().to_str().unwrap());
+pub async fn process_map_directory(buf: PathBuf) -> Result<(PathBuf, String), IndexError> {
+    debug!("Processing {}", buf.display());
     let inner_buf = buf.clone();
     let async_hash = tokio::task::spawn_blocking(move || {
         match generate_hash(inner_buf.clone()) {
@@ -115,51 +114,59 @@ pub async fn process_map_directory(entry: std::fs::DirEntry) -> Result<(PathBuf,
     }
 }
 
+/// Cheap fingerprint of a map directory, used to skip rehashing unchanged maps
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapFingerprint {
+    pub files: u32,
+    pub size: u64,
+    pub modified: u64,
+    pub info_hash: String,
+}
+
+pub fn fingerprint(path: &Path) -> std::io::Result<MapFingerprint> {
+    let mut files = 0u32;
+    let mut size = 0u64;
+    let mut modified = 0u64;
+    for entry in std::fs::read_dir(path)? {
+        let metadata = entry?.metadata()?;
+        files += 1;
+        size += metadata.len();
+        let file_modified = metadata.modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+        modified = modified.max(file_modified);
+    }
+    let info_hash = sha1::Sha1::from(std::fs::read(find_info_file(path))?).hexdigest();
+    Ok(MapFingerprint {
+        files,
+        size,
+        modified,
+        info_hash,
+    })
+}
+
 pub fn generate_hash(path: PathBuf) -> Result<String, IndexError> {
-    let mut info_file_path = path.clone();
-    info_file_path.push("info.dat");
+    let info_file_path = find_info_file(path.as_path());
 
-    match std::fs::File::open(info_file_path.clone()) {
-        Ok(mut info_file) => {
-            let mut info_file_data = String::new();
-            info_file.read_to_string(&mut info_file_data).ok();
-            match serde_json::from_str(info_file_data.as_str()) {
+    match std::fs::read(info_file_path.clone()) {
+        Ok(info_file_data) => {
+            match serde_json::from_slice(info_file_data.as_ref()) {
                 Ok(value) => {
-                    let value: serde_json::Value = value;
-                    let filenames = value.as_object()
-                        .and_then(|obj| obj.get("_difficultyBeatmapSets"))
-                        .and_then(|value| value.as_array())
-                        .map(|vec| vec.iter()
-                            .filter_map(|value| value.as_object())
-                            .filter_map(|obj| obj.get("_difficultyBeatmaps"))
-                            .filter_map(|value| value.as_array())
-                            .flat_map(|array| array.iter()
-                                .filter_map(|value| value.as_object())
-                                .filter_map(|obj| obj.get("_beatmapFilename"))
-                                .filter_map(|value| value.as_str())
-                                .map(|str| str.to_string())
-                                .collect::<Vec<String>>())
-                            .collect::<Vec<String>>());
+                    let filenames = hashed_filenames(&value);
                     if let Some(filenames) = filenames {
-                        let mut file_bufs = Vec::new();
-                        for byte in info_file_data.as_bytes() {
-                            file_bufs.push(byte.clone());
-                        }
+                        let mut hasher = sha1::Sha1::new();
+                        hasher.update(info_file_data.as_ref());
+                        let mut buf = vec![0u8; HASH_BUFFER_SIZE];
                         for filename in filenames {
                             let mut file_data_path = path.clone();
                             file_data_path.push(filename);
-                            match std::fs::read(file_data_path.clone()) {
-                                Ok(buf) => {
-                                    for byte in buf {
-                                        file_bufs.push(byte);
-                                    }
-                                }
-                                Err(err) => {
-                                    return Err(IndexError::InvalidDifficulty(err, file_data_path));
-                                }
+                            if let Err(err) = hash_file(&mut hasher, file_data_path.as_path(), buf.as_mut_slice()) {
+                                return Err(IndexError::InvalidDifficulty(err, file_data_path));
                             }
                         }
-                        Ok(sha1::Sha1::from(file_bufs).hexdigest())
+                        Ok(hasher.hexdigest())
                     } else {
                         Err(IndexError::InvalidMapInfoDat(info_file_path))
                     }
@@ -173,4 +180,257 @@ pub fn generate_hash(path: PathBuf) -> Result<String, IndexError> {
             Err(IndexError::NotAMap(err, path))
         }
     }
-}
\ No newline at end of file
+}
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Feeds a file into the hasher chunk by chunk
+fn hash_file(hasher: &mut sha1::Sha1, path: &Path, buf: &mut [u8]) -> std::io::Result<()> {
+    let mut file = std::fs::File::open(path)?;
+    loop {
+        let read = match file.read(buf) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err)
+        };
+        hasher.update(&buf[..read]);
+    }
+}
+
+/// Amount of bytes the hash of a map covers, taken from the file sizes without reading the files
+pub fn hashed_size(path: &Path) -> Result<u64, IndexError> {
+    let info_file_path = find_info_file(path);
+    let info_file_data = std::fs::read(info_file_path.as_path())
+        .map_err(|err| IndexError::NotAMap(err, path.to_path_buf()))?;
+    let value = serde_json::from_slice(info_file_data.as_ref())
+        .map_err(|err| IndexError::MapJsonError(err, path.to_path_buf()))?;
+    let filenames = hashed_filenames(&value)
+        .ok_or(IndexError::InvalidMapInfoDat(info_file_path))?;
+    let mut size = info_file_data.len() as u64;
+    for filename in filenames {
+        let file_data_path = path.join(filename);
+        size += std::fs::metadata(file_data_path.as_path())
+            .map_err(|err| IndexError::InvalidDifficulty(err, file_data_path))?
+            .len();
+    }
+    Ok(size)
+}
+
+/// Calculates the map hash of a zipped map, as it would be after extracting it.
+pub fn generate_hash_from_zip(path: &Path) -> Result<String, IndexError> {
+    let file = std::fs::File::open(path)
+        .map_err(IndexError::CannotReadMap)?;
+    let mut archive = zip::ZipArchive::new(file)
+        .map_err(|err| IndexError::CannotReadMap(err.into()))?;
+    let info_file_name = archive.file_names()
+        .find(|name| name.eq_ignore_ascii_case("info.dat"))
+        .map(|name| name.to_string());
+    let info_file_name = match info_file_name {
+        Some(name) => name,
+        None => return Err(IndexError::NotAMap(std::io::Error::new(std::io::ErrorKind::NotFound, "info.dat not found"),
+                                               path.to_path_buf()))
+    };
+    let mut info_file_data = Vec::new();
+    archive.by_name(info_file_name.as_str())
+        .and_then(|mut file| file.read_to_end(&mut info_file_data).map_err(|err| err.into()))
+        .map_err(|err| IndexError::CannotReadMap(err.into()))?;
+    let value: serde_json::Value = serde_json::from_slice(info_file_data.as_ref())
+        .map_err(|err| IndexError::MapJsonError(err, path.to_path_buf()))?;
+    let filenames = hashed_filenames(&value)
+        .ok_or_else(|| IndexError::InvalidMapInfoDat(path.to_path_buf()))?;
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(info_file_data.as_ref());
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+    for filename in filenames {
+        let mut file = archive.by_name(filename.as_str())
+            .map_err(|err| IndexError::InvalidDifficulty(err.into(), path.to_path_buf()))?;
+        loop {
+            match file.read(buf.as_mut_slice()) {
+                Ok(0) => break,
+                Ok(read) => hasher.update(&buf[..read]),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(IndexError::InvalidDifficulty(err, path.to_path_buf()))
+            }
+        }
+    }
+    Ok(hasher.hexdigest())
+}
+
+/// Locates the info.dat of a map directory, which is called Info.dat in newer maps
+fn find_info_file(path: &Path) -> PathBuf {
+    let mut info_file_path = path.to_path_buf();
+    info_file_path.push("info.dat");
+    if !info_file_path.exists() {
+        let mut capitalized_path = path.to_path_buf();
+        capitalized_path.push("Info.dat");
+        if capitalized_path.exists() {
+            return capitalized_path;
+        }
+    }
+    info_file_path
+}
+
+fn is_v4(value: &serde_json::Value) -> bool {
+    value.get("version")
+        .and_then(|value| value.as_str())
+        .map(|version| version.starts_with("4."))
+        .unwrap_or(false)
+}
+
+/// Returns the files which are hashed after the info.dat, in order.
+/// v2 info.dat files (also used by v3 maps) hash every `_beatmapFilename`,
+/// v4 info.dat files hash the audio data file followed by the beatmap and lightshow file of every difficulty.
+fn hashed_filenames(value: &serde_json::Value) -> Option<Vec<String>> {
+    let obj = value.as_object()?;
+    if is_v4(value) {
+        v4_hashed_filenames(obj)
+    } else {
+        v2_hashed_filenames(obj)
+    }
+}
+
+fn v2_hashed_filenames(obj: &serde_json::Map<String, serde_json::Value>) -> Option<Vec<String>> {
+    obj.get("_difficultyBeatmapSets")
+        .and_then(|value| value.as_array())
+        .map(|vec| vec.iter()
+            .filter_map(|value| value.as_object())
+            .filter_map(|obj| obj.get("_difficultyBeatmaps"))
+            .filter_map(|value| value.as_array())
+            .flat_map(|array| array.iter()
+                .filter_map(|value| value.as_object())
+                .filter_map(|obj| obj.get("_beatmapFilename"))
+                .filter_map(|value| value.as_str())
+                .map(|str| str.to_string())
+                .collect::<Vec<String>>())
+            .collect::<Vec<String>>())
+}
+
+fn v4_hashed_filenames(obj: &serde_json::Map<String, serde_json::Value>) -> Option<Vec<String>> {
+    let audio_data_filename = obj.get("audio")
+        .and_then(|value| value.as_object())
+        .and_then(|obj| obj.get("audioDataFilename"))
+        .and_then(|value| value.as_str())?;
+    let difficulties = obj.get("difficultyBeatmaps")
+        .and_then(|value| value.as_array())?;
+    let mut filenames = vec![audio_data_filename.to_string()];
+    for difficulty in difficulties.iter().filter_map(|value| value.as_object()) {
+        for key in ["beatmapDataFilename", "lightshowDataFilename"].iter() {
+            let filename = difficulty.get(*key)
+                .and_then(|value| value.as_str())?;
+            filenames.push(filename.to_string());
+        }
+    }
+    Some(filenames)
+}
+
+/// Map details parsed from a local info.dat
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalMapInfo {
+    pub song_name: String,
+    pub song_sub_name: String,
+    pub song_author_name: String,
+    pub level_author_name: String,
+    pub bpm: f32,
+    pub duration: Option<f32>,
+    pub difficulties: Vec<MapDifficulty>,
+    pub cover_image_filename: Option<String>,
+    pub environment_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapDifficulty {
+    pub characteristic: String,
+    pub difficulty: String,
+}
+
+pub fn read_map_info(path: &Path) -> Result<LocalMapInfo, IndexError> {
+    let info_file_path = find_info_file(path);
+    let info_file_data = std::fs::read(info_file_path.clone())
+        .map_err(|err| IndexError::NotAMap(err, path.to_path_buf()))?;
+    let value: serde_json::Value = serde_json::from_slice(info_file_data.as_ref())
+        .map_err(|err| IndexError::MapJsonError(err, path.to_path_buf()))?;
+    let info = if is_v4(&value) {
+        v4_map_info(&value)
+    } else {
+        v2_map_info(&value)
+    };
+    info.ok_or(IndexError::InvalidMapInfoDat(info_file_path))
+}
+
+fn json_str(value: &serde_json::Value, pointer: &str) -> Option<String> {
+    value.pointer(pointer)
+        .and_then(|value| value.as_str())
+        .map(|str| str.to_string())
+}
+
+fn v2_map_info(value: &serde_json::Value) -> Option<LocalMapInfo> {
+    let difficulties = value.get("_difficultyBeatmapSets")
+        .and_then(|value| value.as_array())?
+        .iter()
+        .flat_map(|set| {
+            let characteristic = json_str(set, "/_beatmapCharacteristicName").unwrap_or_default();
+            set.get("_difficultyBeatmaps")
+                .and_then(|value| value.as_array())
+                .map(|array| array.iter()
+                    .filter_map(|difficulty| json_str(difficulty, "/_difficulty"))
+                    .map(|difficulty| MapDifficulty {
+                        characteristic: characteristic.clone(),
+                        difficulty,
+                    })
+                    .collect::<Vec<MapDifficulty>>())
+                .unwrap_or_default()
+        })
+        .collect();
+    Some(LocalMapInfo {
+        song_name: json_str(value, "/_songName").unwrap_or_default(),
+        song_sub_name: json_str(value, "/_songSubName").unwrap_or_default(),
+        song_author_name: json_str(value, "/_songAuthorName").unwrap_or_default(),
+        level_author_name: json_str(value, "/_levelAuthorName").unwrap_or_default(),
+        bpm: value.pointer("/_beatsPerMinute")
+            .and_then(|value| value.as_f64())
+            .unwrap_or_default() as f32,
+        duration: None,
+        difficulties,
+        cover_image_filename: json_str(value, "/_coverImageFilename"),
+        environment_name: json_str(value, "/_environmentName"),
+    })
+}
+
+fn v4_map_info(value: &serde_json::Value) -> Option<LocalMapInfo> {
+    let beatmaps = value.get("difficultyBeatmaps")
+        .and_then(|value| value.as_array())?;
+    let difficulties = beatmaps.iter()
+        .filter_map(|beatmap| Some(MapDifficulty {
+            characteristic: json_str(beatmap, "/characteristic")?,
+            difficulty: json_str(beatmap, "/difficulty")?,
+        }))
+        .collect();
+    let mut mappers: Vec<String> = Vec::new();
+    for mapper in beatmaps.iter()
+        .filter_map(|beatmap| beatmap.pointer("/beatmapAuthors/mappers"))
+        .filter_map(|value| value.as_array())
+        .flatten()
+        .filter_map(|value| value.as_str()) {
+        if !mappers.iter().any(|existing| existing.eq(mapper)) {
+            mappers.push(mapper.to_string());
+        }
+    }
+    Some(LocalMapInfo {
+        song_name: json_str(value, "/song/title").unwrap_or_default(),
+        song_sub_name: json_str(value, "/song/subTitle").unwrap_or_default(),
+        song_author_name: json_str(value, "/song/author").unwrap_or_default(),
+        level_author_name: mappers.join(", "),
+        bpm: value.pointer("/audio/bpm")
+            .and_then(|value| value.as_f64())
+            .unwrap_or_default() as f32,
+        duration: value.pointer("/audio/songDuration")
+            .and_then(|value| value.as_f64())
+            .map(|duration| duration as f32),
+        difficulties,
+        cover_image_filename: json_str(value, "/coverImageFilename"),
+        environment_name: json_str(value, "/environmentNames/0"),
+    })
+}
@@ -0,0 +1,267 @@
+use crate::config::{MapData, MapIndex};
+use crate::map_index::LocalMapInfo;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use uuid::Uuid;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapQuery {
+    /// Case-insensitive search over song name, sub name, song author and mapper
+    pub q: Option<String>,
+    pub difficulty: Option<String>,
+    pub ranked: Option<bool>,
+    pub bpm_min: Option<f32>,
+    pub bpm_max: Option<f32>,
+    pub status: Option<MapStatus>,
+    pub sort: Option<MapSort>,
+    #[serde(default)]
+    pub descending: bool,
+    #[serde(default)]
+    pub page: usize,
+    pub page_size: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum MapStatus {
+    Valid,
+    Unknown,
+    Invalid,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub enum MapSort {
+    SongName,
+    SongAuthor,
+    Mapper,
+    Bpm,
+    Uploaded,
+    Path,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapQueryRequest {
+    pub installation: Uuid,
+    #[serde(flatten)]
+    pub query: MapQuery,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapQueryResult {
+    pub installation: Uuid,
+    pub total: usize,
+    pub page: usize,
+    pub page_size: usize,
+    pub maps: Vec<MapData>,
+}
+
+impl MapQuery {
+    fn matches(&self, map: &MapData) -> bool {
+        if let Some(status) = self.status {
+            if map.status() != status {
+                return false;
+            }
+        }
+        if let Some(ranked) = self.ranked {
+            let map_ranked = match map {
+                MapData::Valid(map) => map.ranked,
+                _ => false
+            };
+            if map_ranked != ranked {
+                return false;
+            }
+        }
+        let needs_info = self.q.is_some() || self.difficulty.is_some() ||
+            self.bpm_min.is_some() || self.bpm_max.is_some();
+        if !needs_info {
+            return true;
+        }
+        let info = match map.info() {
+            Some(info) => info,
+            None => return false
+        };
+        if let Some(q) = self.q.as_ref() {
+            let q = q.to_lowercase();
+            let found = [&info.song_name, &info.song_sub_name, &info.song_author_name, &info.level_author_name]
+                .iter()
+                .any(|field| field.to_lowercase().contains(q.as_str()));
+            if !found {
+                return false;
+            }
+        }
+        if let Some(difficulty) = self.difficulty.as_ref() {
+            if !info.difficulties.iter().any(|entry| entry.difficulty.eq_ignore_ascii_case(difficulty)) {
+                return false;
+            }
+        }
+        if self.bpm_min.map(|bpm_min| info.bpm < bpm_min).unwrap_or(false) ||
+            self.bpm_max.map(|bpm_max| info.bpm > bpm_max).unwrap_or(false) {
+            return false;
+        }
+        true
+    }
+
+    fn compare(&self, sort: MapSort, a: &MapData, b: &MapData) -> Ordering {
+        let text = |map: &MapData, field: fn(&LocalMapInfo) -> &String| map.info()
+            .map(|info| field(info).to_lowercase());
+        match sort {
+            MapSort::SongName => text(a, |info| &info.song_name).cmp(&text(b, |info| &info.song_name)),
+            MapSort::SongAuthor => text(a, |info| &info.song_author_name).cmp(&text(b, |info| &info.song_author_name)),
+            MapSort::Mapper => text(a, |info| &info.level_author_name).cmp(&text(b, |info| &info.level_author_name)),
+            MapSort::Bpm => {
+                let bpm = |map: &MapData| map.info().map(|info| info.bpm).unwrap_or_default();
+                bpm(a).partial_cmp(&bpm(b)).unwrap_or(Ordering::Equal)
+            }
+            MapSort::Uploaded => {
+                let uploaded = |map: &MapData| match map {
+                    MapData::Valid(map) => map.uploaded,
+                    _ => None
+                };
+                uploaded(a).cmp(&uploaded(b))
+            }
+            MapSort::Path => a.as_ref().cmp(b.as_ref())
+        }
+    }
+
+    pub fn execute(&self, installation: Uuid, index: &MapIndex) -> MapQueryResult {
+        let mut maps = index.maps.iter()
+            .filter(|map| self.matches(map))
+            .collect::<Vec<&MapData>>();
+        if let Some(sort) = self.sort {
+            maps.sort_by(|a, b| self.compare(sort, a, b));
+            if self.descending {
+                maps.reverse();
+            }
+        }
+        let page_size = self.page_size.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        MapQueryResult {
+            installation,
+            total: maps.len(),
+            page: self.page,
+            page_size,
+            maps: maps.into_iter()
+                .skip(self.page.saturating_mul(page_size))
+                .take(page_size)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MapMetadata, UnknownMap, UnknownReason};
+    use crate::map_index::MapDifficulty;
+    use std::path::PathBuf;
+
+    fn info(song_name: &str, bpm: f32) -> LocalMapInfo {
+        LocalMapInfo {
+            song_name: song_name.to_string(),
+            song_sub_name: String::new(),
+            song_author_name: "Artist".to_string(),
+            level_author_name: "Mapper".to_string(),
+            bpm,
+            duration: None,
+            difficulties: vec![MapDifficulty {
+                characteristic: "Standard".to_string(),
+                difficulty: "Expert".to_string(),
+            }],
+            cover_image_filename: None,
+            environment_name: None,
+        }
+    }
+
+    fn index() -> MapIndex {
+        let valid = |id: u32, song_name: &str, bpm: f32, ranked: bool| MapData::Valid(MapMetadata {
+            path: PathBuf::from(song_name),
+            hash: format!("{:040x}", id),
+            id,
+            info: Some(info(song_name, bpm)),
+            ranked,
+            qualified: false,
+            automapper: false,
+            uploaded: None,
+        });
+        MapIndex {
+            maps: vec![
+                valid(1, "Charlie", 140f32, true),
+                valid(2, "Alpha", 120f32, false),
+                valid(3, "Bravo", 180f32, true),
+                MapData::Unknown(UnknownMap {
+                    path: PathBuf::from("Delta"),
+                    hash: "d".repeat(40),
+                    info: Some(info("Delta", 100f32)),
+                    reason: UnknownReason::NotFound,
+                    last_checked: None,
+                    attempts: 0,
+                }),
+                MapData::Invalid(PathBuf::from("Echo")),
+            ],
+            fingerprints: Default::default(),
+        }
+    }
+
+    fn paths(result: &MapQueryResult) -> Vec<PathBuf> {
+        result.maps.iter().map(|map| map.as_ref().clone()).collect()
+    }
+
+    #[test]
+    fn filters_by_status_ranked_and_bpm() {
+        let query = MapQuery {
+            status: Some(MapStatus::Valid),
+            ranked: Some(true),
+            bpm_min: Some(150f32),
+            ..MapQuery::default()
+        };
+        let result = query.execute(Uuid::nil(), &index());
+        assert_eq!(result.total, 1);
+        assert_eq!(paths(&result), vec![PathBuf::from("Bravo")]);
+    }
+
+    #[test]
+    fn searches_case_insensitively_and_skips_maps_without_info() {
+        let query = MapQuery {
+            q: Some("MAPPER".to_string()),
+            ..MapQuery::default()
+        };
+        assert_eq!(query.execute(Uuid::nil(), &index()).total, 4);
+    }
+
+    #[test]
+    fn pages_sorted_results() {
+        let query = MapQuery {
+            status: Some(MapStatus::Valid),
+            sort: Some(MapSort::SongName),
+            page: 1,
+            page_size: Some(2),
+            ..MapQuery::default()
+        };
+        let result = query.execute(Uuid::nil(), &index());
+        assert_eq!(result.total, 3);
+        assert_eq!(result.page_size, 2);
+        assert_eq!(paths(&result), vec![PathBuf::from("Charlie")]);
+    }
+
+    #[test]
+    fn clamps_page_size_and_survives_huge_pages() {
+        let query = MapQuery {
+            page: usize::MAX,
+            page_size: Some(0),
+            ..MapQuery::default()
+        };
+        let result = query.execute(Uuid::nil(), &index());
+        assert_eq!(result.page_size, 1);
+        assert!(result.maps.is_empty());
+        let query = MapQuery {
+            page_size: Some(MAX_PAGE_SIZE + 1),
+            ..MapQuery::default()
+        };
+        assert_eq!(query.execute(Uuid::nil(), &index()).page_size, MAX_PAGE_SIZE);
+    }
+}
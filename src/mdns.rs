@@ -0,0 +1,322 @@
+use crate::daemon_settings::LanSettings;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::Duration;
+use log::{debug, info, warn};
+
+const MDNS_ADDRESS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE_TYPE: &str = "_aiosaber._tcp.local";
+const RECORD_TTL: u32 = 120;
+const ANNOUNCEMENTS: usize = 2;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// Minimal mDNS responder which advertises the LAN listener as `_aiosaber._tcp.local`,
+/// it answers PTR, SRV, TXT and A questions for the service and announces it on start
+pub struct MdnsResponder {
+    instance: String,
+    host: String,
+    address: Ipv4Addr,
+    port: u16,
+    version: String,
+}
+
+fn hostname() -> String {
+    let name = std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .unwrap_or_default();
+    let name: String = name.trim()
+        .chars()
+        .map(|char| if char.is_ascii_alphanumeric() { char } else { '-' })
+        .collect();
+    if name.is_empty() {
+        "aiosaber".to_string()
+    } else {
+        name
+    }
+}
+
+/// Address of the interface which routes to the mDNS group
+fn lan_address() -> Option<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MDNS_ADDRESS, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(address) if !address.is_loopback() && !address.is_unspecified() => Some(address),
+        _ => None
+    }
+}
+
+fn bind_multicast(interface: Ipv4Addr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Other responders like Bonjour or Avahi usually share the port
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_ADDRESS, &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label);
+    }
+    encoded.push(0);
+    encoded
+}
+
+/// Reads a possibly compressed name, returns it with the position after it
+fn read_name(packet: &[u8], mut position: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let length = *packet.get(position)? as usize;
+        if length == 0 {
+            position += 1;
+            break;
+        }
+        if length & 0xC0 == 0xC0 {
+            end.get_or_insert(position + 2);
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            position = ((length & 0x3F) << 8) | *packet.get(position + 1)? as usize;
+            continue;
+        }
+        let label = packet.get(position + 1..position + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        position += 1 + length;
+    }
+    Some((labels.join("."), end.unwrap_or(position)))
+}
+
+fn read_u16(packet: &[u8], position: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*packet.get(position)?, *packet.get(position + 1)?]))
+}
+
+/// Questions of a query packet as name and type, responses of other hosts are ignored
+fn questions(packet: &[u8]) -> Vec<(String, u16)> {
+    let mut questions = Vec::new();
+    let (flags, count) = match (read_u16(packet, 2), read_u16(packet, 4)) {
+        (Some(flags), Some(count)) => (flags, count),
+        _ => return questions
+    };
+    if flags & 0x8000 != 0 {
+        return questions;
+    }
+    let mut position = 12;
+    for _ in 0..count {
+        let (name, end) = match read_name(packet, position) {
+            Some(name) => name,
+            None => break
+        };
+        let question_type = match read_u16(packet, end) {
+            Some(question_type) => question_type,
+            None => break
+        };
+        questions.push((name, question_type));
+        position = end + 4;
+    }
+    questions
+}
+
+fn push_record(packet: &mut Vec<u8>, name: &str, record_type: u16, class: u16, data: &[u8]) {
+    packet.extend_from_slice(encode_name(name).as_ref());
+    packet.extend_from_slice(&record_type.to_be_bytes());
+    packet.extend_from_slice(&class.to_be_bytes());
+    packet.extend_from_slice(&RECORD_TTL.to_be_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+}
+
+impl MdnsResponder {
+    pub fn new(settings: &LanSettings, version: String) -> Option<MdnsResponder> {
+        let address = match settings.address {
+            IpAddr::V4(address) if !address.is_unspecified() => Some(address),
+            IpAddr::V4(_) => lan_address(),
+            IpAddr::V6(_) => None
+        };
+        let address = match address {
+            Some(address) => address,
+            None => {
+                warn!("No IPv4 LAN address found, the daemon won't be advertised with mDNS");
+                return None;
+            }
+        };
+        let host = hostname();
+        Some(MdnsResponder {
+            instance: format!("AIOSaber {}.{}", host, SERVICE_TYPE),
+            host: format!("{}.local", host),
+            address,
+            port: settings.port,
+            version,
+        })
+    }
+
+    pub async fn advertise(&self) {
+        if let Err(err) = self.run().await {
+            warn!("mDNS advertisement stopped: {}", err);
+        }
+    }
+
+    async fn run(&self) -> std::io::Result<()> {
+        let socket = bind_multicast(self.address)?;
+        let group = SocketAddr::from((MDNS_ADDRESS, MDNS_PORT));
+        info!("Advertising {} at {}:{}", self.instance, self.address, self.port);
+        for _ in 0..ANNOUNCEMENTS {
+            socket.send_to(self.response(0).as_ref(), group).await?;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        let mut buffer = [0u8; 9000];
+        loop {
+            let (length, peer) = socket.recv_from(&mut buffer).await?;
+            let packet = &buffer[..length];
+            if !questions(packet).iter().any(|(name, question_type)| self.answers(name, *question_type)) {
+                continue;
+            }
+            debug!("Answering mDNS query of {}", peer);
+            // Queries which don't come from the mDNS port expect a direct answer with their id
+            let (id, target) = if peer.port() == MDNS_PORT {
+                (0, group)
+            } else {
+                (read_u16(packet, 0).unwrap_or(0), peer)
+            };
+            if let Err(err) = socket.send_to(self.response(id).as_ref(), target).await {
+                warn!("Cannot send mDNS response to {}: {}", target, err);
+            }
+        }
+    }
+
+    fn answers(&self, name: &str, question_type: u16) -> bool {
+        let types: &[u16] = if name.eq_ignore_ascii_case(SERVICE_TYPE) {
+            &[TYPE_PTR, TYPE_ANY]
+        } else if name.eq_ignore_ascii_case(self.instance.as_str()) {
+            &[TYPE_SRV, TYPE_TXT, TYPE_ANY]
+        } else if name.eq_ignore_ascii_case(self.host.as_str()) {
+            &[TYPE_A, TYPE_ANY]
+        } else {
+            &[]
+        };
+        types.contains(&question_type)
+    }
+
+    /// Response with all records of the service
+    fn response(&self, id: u16) -> Vec<u8> {
+        let mut packet = Vec::new();
+        for value in [id, 0x8400, 0, 4, 0, 0].iter() {
+            packet.extend_from_slice(&value.to_be_bytes());
+        }
+        push_record(&mut packet, SERVICE_TYPE, TYPE_PTR, CLASS_IN, encode_name(self.instance.as_str()).as_ref());
+
+        let mut service = vec![0, 0, 0, 0];
+        service.extend_from_slice(&self.port.to_be_bytes());
+        service.extend_from_slice(encode_name(self.host.as_str()).as_ref());
+        push_record(&mut packet, self.instance.as_str(), TYPE_SRV, CLASS_IN | CACHE_FLUSH, service.as_ref());
+
+        let mut text = Vec::new();
+        for entry in [String::from("tls=1"), format!("version={}", self.version)].iter() {
+            let entry = &entry.as_bytes()[..entry.len().min(255)];
+            text.push(entry.len() as u8);
+            text.extend_from_slice(entry);
+        }
+        push_record(&mut packet, self.instance.as_str(), TYPE_TXT, CLASS_IN | CACHE_FLUSH, text.as_ref());
+
+        push_record(&mut packet, self.host.as_str(), TYPE_A, CLASS_IN | CACHE_FLUSH, &self.address.octets());
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(names: &[&[u8]], question_type: u16) -> Vec<u8> {
+        let mut packet = vec![0, 0, 0, 0];
+        packet.extend_from_slice(&(names.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0; 6]);
+        for name in names {
+            packet.extend_from_slice(name);
+            packet.extend_from_slice(&question_type.to_be_bytes());
+            packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        }
+        packet
+    }
+
+    #[test]
+    fn reads_plain_and_compressed_names() {
+        let mut packet = encode_name(SERVICE_TYPE);
+        let pointer = packet.len();
+        packet.extend_from_slice(&[4, b'h', b'o', b's', b't', 0xC0, 0]);
+        assert_eq!(read_name(&packet, 0), Some((SERVICE_TYPE.to_string(), pointer)));
+        assert_eq!(read_name(&packet, pointer), Some((format!("host.{}", SERVICE_TYPE), packet.len())));
+    }
+
+    #[test]
+    fn rejects_compression_loops() {
+        assert_eq!(read_name(&[0xC0, 0], 0), None);
+        assert_eq!(read_name(&[0xC0, 2, 0xC0, 0], 0), None);
+    }
+
+    #[test]
+    fn rejects_truncated_names() {
+        assert_eq!(read_name(&[], 0), None);
+        assert_eq!(read_name(&[5, b'l', b'o'], 0), None);
+        assert_eq!(read_name(&[4, b'h', b'o', b's', b't'], 0), None);
+        assert_eq!(read_name(&[0xC0], 0), None);
+    }
+
+    #[test]
+    fn parses_questions_of_queries() {
+        let service = encode_name(SERVICE_TYPE);
+        let packet = query(&[service.as_ref(), &[0xC0, 12]], TYPE_PTR);
+        assert_eq!(questions(&packet), vec![
+            (SERVICE_TYPE.to_string(), TYPE_PTR),
+            (SERVICE_TYPE.to_string(), TYPE_PTR),
+        ]);
+    }
+
+    #[test]
+    fn ignores_responses_and_stops_at_truncated_questions() {
+        let service = encode_name(SERVICE_TYPE);
+        let mut response = query(&[service.as_ref()], TYPE_PTR);
+        response[2] = 0x84;
+        assert!(questions(&response).is_empty());
+
+        let mut truncated = query(&[service.as_ref(), service.as_ref()], TYPE_PTR);
+        truncated.truncate(truncated.len() - 5);
+        assert_eq!(questions(&truncated), vec![(SERVICE_TYPE.to_string(), TYPE_PTR)]);
+        assert!(questions(&[0, 0, 0]).is_empty());
+    }
+
+    #[test]
+    fn answers_own_records_only() {
+        let settings = LanSettings {
+            address: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)),
+            ..LanSettings::default()
+        };
+        let responder = MdnsResponder::new(&settings, "1.0.0".to_string()).unwrap();
+        let response = responder.response(7);
+        assert_eq!(read_u16(&response, 0), Some(7));
+        assert!(questions(&response).is_empty());
+        assert!(responder.answers("_AIOSABER._tcp.local", TYPE_PTR));
+        assert!(!responder.answers(SERVICE_TYPE, TYPE_A));
+        assert!(!responder.answers("_other._tcp.local", TYPE_PTR));
+    }
+}
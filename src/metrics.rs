@@ -0,0 +1,196 @@
+use crate::config::{DaemonConfig, MapData};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Upper bounds in seconds, shared by BeatSaver requests and map downloads
+const DURATION_BUCKETS: [f64; 11] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS.iter()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Counters since the daemon started, gauges are read from the config when rendering
+#[derive(Default)]
+struct Metrics {
+    downloads: BTreeMap<&'static str, u64>,
+    download_bytes: u64,
+    download_duration: Histogram,
+    download_failures: BTreeMap<&'static str, u64>,
+    install_failures: BTreeMap<(Uuid, &'static str), u64>,
+    beatsaver_duration: Histogram,
+    /// Keyed by status code, `error` if no response was received
+    beatsaver_responses: BTreeMap<String, u64>,
+    watcher_events: BTreeMap<(Uuid, &'static str), u64>,
+}
+
+fn with_metrics(change: impl FnOnce(&mut Metrics)) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        change(&mut metrics);
+    }
+}
+
+/// A finished map download, `failure` is the error kind if it failed
+pub fn record_download(duration: Duration, failure: Option<&'static str>) {
+    with_metrics(|metrics| {
+        *metrics.downloads.entry(if failure.is_some() { "failure" } else { "success" }).or_default() += 1;
+        metrics.download_duration.observe(duration);
+        if let Some(kind) = failure {
+            *metrics.download_failures.entry(kind).or_default() += 1;
+        }
+    });
+}
+
+pub fn record_download_bytes(bytes: u64) {
+    with_metrics(|metrics| metrics.download_bytes += bytes);
+}
+
+/// A requested map which couldn't be downloaded before the download itself started
+pub fn record_download_failure(kind: &'static str) {
+    with_metrics(|metrics| *metrics.download_failures.entry(kind).or_default() += 1);
+}
+
+pub fn record_install_failure(installation: &Uuid, kind: &'static str) {
+    with_metrics(|metrics| *metrics.install_failures.entry((*installation, kind)).or_default() += 1);
+}
+
+pub fn record_beatsaver_request(duration: Duration, status: Option<u16>) {
+    with_metrics(|metrics| {
+        metrics.beatsaver_duration.observe(duration);
+        let status = status.map(|status| status.to_string()).unwrap_or_else(|| "error".to_string());
+        *metrics.beatsaver_responses.entry(status).or_default() += 1;
+    });
+}
+
+pub fn record_watcher_event(installation: &Uuid, kind: &'static str) {
+    with_metrics(|metrics| *metrics.watcher_events.entry((*installation, kind)).or_default() += 1);
+}
+
+fn header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(output, "# HELP {} {}", name, help).ok();
+    writeln!(output, "# TYPE {} {}", name, metric_type).ok();
+}
+
+fn histogram(output: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    header(output, name, "histogram", help);
+    for (bucket, bound) in histogram.buckets.iter().zip(DURATION_BUCKETS.iter()) {
+        writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket).ok();
+    }
+    writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count).ok();
+    writeln!(output, "{}_sum {}", name, histogram.sum).ok();
+    writeln!(output, "{}_count {}", name, histogram.count).ok();
+}
+
+/// Renders all metrics in the Prometheus text format
+pub async fn render(config: &DaemonConfig) -> String {
+    let mut output = String::new();
+    let installations = config.get_data().await;
+
+    header(&mut output, "aiosaber_installer_queue_depth", "gauge", "Maps waiting for the installer queue of an installation");
+    for data in installations.iter() {
+        writeln!(output, "aiosaber_installer_queue_depth{{installation=\"{}\"}} {}", data.config.id,
+                 crate::config::INSTALLER_QUEUE_SIZE - data.installer_queue.capacity()).ok();
+    }
+    header(&mut output, "aiosaber_index_maps", "gauge", "Maps in the index of an installation by state");
+    for data in installations.iter() {
+        // The index is locked for the whole reindex, its gauge is left out instead of stalling the scrape
+        let map_index = match data.map_index.try_lock() {
+            Ok(map_index) => map_index,
+            Err(_) => continue
+        };
+        let mut states = [("valid", 0), ("unknown", 0), ("invalid", 0)];
+        for map in map_index.maps.iter() {
+            let state = match map {
+                MapData::Valid(_) => 0,
+                MapData::Unknown(_) => 1,
+                MapData::Invalid(_) => 2
+            };
+            states[state].1 += 1;
+        }
+        drop(map_index);
+        for (state, count) in states.iter() {
+            writeln!(output, "aiosaber_index_maps{{installation=\"{}\",state=\"{}\"}} {}", data.config.id, state, count).ok();
+        }
+    }
+
+    let metrics = METRICS.lock().unwrap();
+    header(&mut output, "aiosaber_downloads_total", "counter", "Finished map downloads by result");
+    for (result, count) in metrics.downloads.iter() {
+        writeln!(output, "aiosaber_downloads_total{{result=\"{}\"}} {}", result, count).ok();
+    }
+    header(&mut output, "aiosaber_download_bytes_total", "counter", "Bytes received for map downloads");
+    writeln!(output, "aiosaber_download_bytes_total {}", metrics.download_bytes).ok();
+    histogram(&mut output, "aiosaber_download_duration_seconds", "Duration of map downloads including retries",
+              &metrics.download_duration);
+    header(&mut output, "aiosaber_download_failures_total", "counter", "Failed map downloads by error kind");
+    for (kind, count) in metrics.download_failures.iter() {
+        writeln!(output, "aiosaber_download_failures_total{{kind=\"{}\"}} {}", kind, count).ok();
+    }
+    header(&mut output, "aiosaber_install_failures_total", "counter", "Failed map installs by installation and error kind");
+    for ((installation, kind), count) in metrics.install_failures.iter() {
+        writeln!(output, "aiosaber_install_failures_total{{installation=\"{}\",kind=\"{}\"}} {}", installation, kind, count).ok();
+    }
+    histogram(&mut output, "aiosaber_beatsaver_request_duration_seconds", "Latency of BeatSaver API requests",
+              &metrics.beatsaver_duration);
+    header(&mut output, "aiosaber_beatsaver_responses_total", "counter", "BeatSaver API responses by status code");
+    for (status, count) in metrics.beatsaver_responses.iter() {
+        writeln!(output, "aiosaber_beatsaver_responses_total{{status=\"{}\"}} {}", status, count).ok();
+    }
+    header(&mut output, "aiosaber_watcher_events_total", "counter", "File system events seen by the map folder watchers");
+    for ((installation, kind), count) in metrics.watcher_events.iter() {
+        writeln!(output, "aiosaber_watcher_events_total{{installation=\"{}\",kind=\"{}\"}} {}", installation, kind, count).ok();
+    }
+    drop(metrics);
+
+    #[cfg(not(target_family = "windows"))]
+    render_jemalloc(&mut output);
+    output
+}
+
+#[cfg(not(target_family = "windows"))]
+fn render_jemalloc(output: &mut String) {
+    use jemalloc_ctl::{epoch, stats};
+    // the statistics are cached until the epoch is advanced
+    if let Err(err) = epoch::advance() {
+        log::warn!("Cannot refresh jemalloc statistics: {}", err);
+        return;
+    }
+    let stats = [
+        ("allocated", "Bytes allocated by the daemon", stats::allocated::read()),
+        ("active", "Bytes in active pages", stats::active::read()),
+        ("metadata", "Bytes used for allocator metadata", stats::metadata::read()),
+        ("resident", "Bytes in physically resident pages", stats::resident::read()),
+        ("mapped", "Bytes in mapped extents", stats::mapped::read()),
+        ("retained", "Bytes retained in virtual memory mappings", stats::retained::read()),
+    ];
+    for (name, help, value) in stats.iter() {
+        if let Ok(value) = value {
+            let name = format!("aiosaber_jemalloc_{}_bytes", name);
+            header(output, name.as_str(), "gauge", help);
+            writeln!(output, "{} {}", name, value).ok();
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use log::warn;
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Playlist {
+    #[serde(default)]
+    pub playlist_title: String,
+    #[serde(default)]
+    pub playlist_author: Option<String>,
+    #[serde(default)]
+    pub songs: Vec<PlaylistSong>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistSong {
+    pub hash: Option<String>,
+    pub key: Option<String>,
+    pub song_name: Option<String>,
+}
+
+pub fn playlists_folder(install_location: &str) -> PathBuf {
+    let mut path = PathBuf::from(install_location);
+    path.push("Playlists");
+    path
+}
+
+/// Reads all .bplist and .json playlists of a PC installation
+pub fn read_playlists(install_location: &str) -> Vec<(PathBuf, Playlist)> {
+    let dir = match std::fs::read_dir(playlists_folder(install_location)) {
+        Ok(dir) => dir,
+        Err(_) => return Vec::new()
+    };
+    dir.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension()
+            .map(|extension| extension.eq("bplist") || extension.eq("json"))
+            .unwrap_or(false))
+        .filter_map(|path| {
+            let playlist = std::fs::read(&path).ok()
+                .and_then(|data| match serde_json::from_slice(data.as_ref()) {
+                    Ok(playlist) => Some(playlist),
+                    Err(err) => {
+                        warn!("Invalid playlist {}: {}", path.display(), err);
+                        None
+                    }
+                })?;
+            Some((path, playlist))
+        })
+        .collect()
+}
+
+/// Lowercase hashes of all maps referenced by any playlist
+pub fn playlist_hashes(install_location: &str) -> HashSet<String> {
+    read_playlists(install_location).into_iter()
+        .flat_map(|(_, playlist)| playlist.songs)
+        .filter_map(|song| song.hash)
+        .map(|hash| hash.to_lowercase())
+        .collect()
+}
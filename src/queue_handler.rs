@@ -1,35 +1,41 @@
-use crate::config::{DaemonConfig, LocalData};
+use crate::config::{DaemonConfig, LocalData, MapData, MapMetadata};
 use tokio::sync::mpsc::Receiver;
 use tokio::task::JoinHandle;
-use tokio::sync::Semaphore;
+use tokio::sync::{Mutex, Semaphore};
 use log::{info, error};
 use crate::beatsaver;
-use crate::beatsaver::{MapVersion, BeatSaverMap};
-use crate::websocket_handler::{WebSocketHandler, WebSocketMessage, ResultMsg, ConfigData, ResultMessageData};
-use crate::websocket_handler::ResultMessageData::MapInstallError;
+use crate::beatsaver::{MapVersion, BeatSaverMap, MapDownload};
+use crate::websocket_handler::{WebSocketMessage, ConfigData, MapInstallEvent, MapInstallFailure};
+use crate::sessions::{Sessions, Topic};
 use crate::installer::Installer;
 use std::sync::Arc;
 use thiserror::Error;
+use uuid::Uuid;
+use crate::quota::QuotaError;
 
 pub enum DownloadQueueRequest {
-    Map(String)
+    Map(String),
+    /// Installs a map only on the given installations
+    MapFor(String, Vec<Uuid>),
 }
 
+/// Cloned by the supervisor to restart the handler, all clones share the receiver
+#[derive(Clone)]
 pub struct DownloadQueueHandler {
-    receiver: Receiver<DownloadQueueRequest>,
+    receiver: Arc<Mutex<Receiver<DownloadQueueRequest>>>,
     config: DownloadQueueHandlerConfiguration,
 }
 
 #[derive(Clone)]
 pub struct DownloadQueueHandlerConfiguration {
     config: DaemonConfig,
-    websocket: tokio::sync::broadcast::Sender<warp::ws::Message>,
+    websocket: Sessions,
 }
 
 impl DownloadQueueHandler {
-    pub fn new(receiver: Receiver<DownloadQueueRequest>, config: DaemonConfig, websocket: tokio::sync::broadcast::Sender<warp::ws::Message>) -> DownloadQueueHandler {
+    pub fn new(receiver: Receiver<DownloadQueueRequest>, config: DaemonConfig, websocket: Sessions) -> DownloadQueueHandler {
         DownloadQueueHandler {
-            receiver,
+            receiver: Arc::new(Mutex::new(receiver)),
             config: DownloadQueueHandlerConfiguration {
                 config,
                 websocket,
@@ -37,24 +43,28 @@ impl DownloadQueueHandler {
         }
     }
 
-    fn handle_install_result(config: ConfigData, receiver: tokio::sync::oneshot::Receiver<InstallerQueueResult>,
-                             websocket: tokio::sync::broadcast::Sender<warp::ws::Message>) -> JoinHandle<()> {
+    fn handle_install_result(config: ConfigData, map: String, receiver: tokio::sync::oneshot::Receiver<InstallerQueueResult>,
+                             websocket: Sessions) -> JoinHandle<()> {
         tokio::spawn(async move {
-            match receiver.await {
+            let result = receiver.await;
+            crate::sync::finish_pending(Some(&config.id), map.as_str());
+            match result {
                 Ok(result) => {
                     match result {
                         InstallerQueueResult::Success(map, version) => {
-                            WebSocketHandler::send_static(websocket, WebSocketMessage::ResultResponse(ResultMsg {
-                                action: "InstallMaps".to_string(),
-                                success: true,
-                                data: ResultMessageData::MapInstallSuccess(config.id, map.id, version.hash),
+                            websocket.publish(Topic::Jobs, WebSocketMessage::MapInstalled(MapInstallEvent {
+                                installation: config.id,
+                                map: map.id,
+                                hash: version.hash,
                             }))
                         }
                         InstallerQueueResult::Error(map, _, error) => {
-                            WebSocketHandler::send_static(websocket, WebSocketMessage::ResultResponse(ResultMsg {
-                                action: "InstallMaps".to_string(),
-                                success: false,
-                                data: ResultMessageData::MapInstallError(Some(config.id), map.id, error.to_string()),
+                            crate::status::record_error(&config.id, format!("Installing map {} failed: {}", map.id, error));
+                            crate::metrics::record_install_failure(&config.id, error.kind());
+                            websocket.publish(Topic::Jobs, WebSocketMessage::MapInstallFailed(MapInstallFailure {
+                                installation: Some(config.id),
+                                map: map.id,
+                                error: error.to_string(),
                             }))
                         }
                         InstallerQueueResult::AlreadyInstalled(map, version) => {
@@ -69,57 +79,75 @@ impl DownloadQueueHandler {
         })
     }
 
-    async fn download_map(config: DownloadQueueHandlerConfiguration, id: String) {
+    /// Picks the installations which should receive a map, based on the request targets and their routing rules
+    async fn route_map(config: &DownloadQueueHandlerConfiguration, map: &BeatSaverMap, targets: &Option<Vec<Uuid>>) -> Vec<LocalData> {
+        let mut installers = Vec::new();
+        for data in config.config.get_data().await {
+            let requested = match targets {
+                Some(targets) if !targets.contains(&data.config.id) => continue,
+                Some(_) => true,
+                None => false
+            };
+            match data.config.routing.check(map, requested) {
+                Ok(_) => installers.push(data),
+                Err(rejection) => {
+                    info!("Not installing map {} on {}: {}", map.id, data.config.id, rejection);
+                    crate::sync::finish_pending(Some(&data.config.id), map.id.as_str());
+                    config.websocket.publish(Topic::Jobs, WebSocketMessage::MapInstallFailed(MapInstallFailure {
+                        installation: Some(data.config.id),
+                        map: map.id.clone(),
+                        error: rejection.to_string(),
+                    }))
+                }
+            }
+        }
+        installers
+    }
+
+    async fn download_map(config: DownloadQueueHandlerConfiguration, id: String, targets: Option<Vec<Uuid>>) {
         match beatsaver::resolve_map_by_id(id.as_str()).await {
             Ok(map) => {
+                let installers = DownloadQueueHandler::route_map(&config, &map, &targets).await;
+                if installers.is_empty() {
+                    info!("No installation accepts map {}", map.id);
+                    return;
+                }
                 match beatsaver::retrieve_map_data(&map).await {
                     Ok((version, data)) => {
-                        let installers = config.config.get_data().await;
-                        if installers.is_empty() {
-                            error!("No installers configured");
-                            return;
-                        }
-                        if installers.len() == 1 {
+                        let data = Arc::new(data);
+                        for installer_data in installers {
                             let (tx, rx) = tokio::sync::oneshot::channel();
-                            let installer = installers.first().unwrap();
-                            if let Some(err) = installer.installer_queue
-                                .send(InstallerQueueRequest::create(tx, InstallerQueueData::Map(map, version, data)))
+                            if let Some(err) = installer_data.installer_queue
+                                .send(InstallerQueueRequest::create(tx, InstallerQueueData::Map(map.clone(), version.clone(), data.clone())))
                                 .await
                                 .err() {
                                 error!("Failed to send map data to installer: {}", err);
+                                crate::sync::finish_pending(Some(&installer_data.config.id), map.id.as_str());
                             } else {
-                                DownloadQueueHandler::handle_install_result(installer.config.clone(), rx, config.websocket.clone());
-                            }
-                        } else {
-                            for installer_data in installers {
-                                let (tx, rx) = tokio::sync::oneshot::channel();
-                                if let Some(err) = installer_data.installer_queue
-                                    .send(InstallerQueueRequest::create(tx, InstallerQueueData::Map(map.clone(), version.clone(), data.clone())))
-                                    .await
-                                    .err() {
-                                    error!("Failed to send map data to installer: {}", err);
-                                } else {
-                                    DownloadQueueHandler::handle_install_result(installer_data.config.clone(), rx, config.websocket.clone());
-                                }
+                                DownloadQueueHandler::handle_install_result(installer_data.config.clone(), map.id.clone(),
+                                                                            rx, config.websocket.clone());
                             }
                         }
                     }
                     Err(error) => {
                         error!("BeatSaverDownloadError: {:?}", error);
-                        WebSocketHandler::send_static(config.websocket.clone(), WebSocketMessage::ResultResponse(ResultMsg {
-                            action: "InstallMaps".to_string(),
-                            success: false,
-                            data: MapInstallError(None, id, error.to_string()),
+                        crate::sync::finish_pending(None, map.id.as_str());
+                        config.websocket.publish(Topic::Jobs, WebSocketMessage::MapInstallFailed(MapInstallFailure {
+                            installation: None,
+                            map: id,
+                            error: error.to_string(),
                         }))
                     }
                 }
             }
             Err(error) => {
                 error!("BeatSaverError: {:?}", error);
-                WebSocketHandler::send_static(config.websocket.clone(), WebSocketMessage::ResultResponse(ResultMsg {
-                    action: "InstallMaps".to_string(),
-                    success: false,
-                    data: MapInstallError(None, id, error.to_string()),
+                crate::metrics::record_download_failure(error.kind());
+                crate::sync::finish_pending(None, id.as_str());
+                config.websocket.publish(Topic::Jobs, WebSocketMessage::MapInstallFailed(MapInstallFailure {
+                    installation: None,
+                    map: id,
+                    error: error.to_string(),
                 }))
             }
         }
@@ -127,16 +155,18 @@ impl DownloadQueueHandler {
 
     async fn handle_request(config: DownloadQueueHandlerConfiguration, request: DownloadQueueRequest) {
         match request {
-            DownloadQueueRequest::Map(map) => DownloadQueueHandler::download_map(config, map).await
+            DownloadQueueRequest::Map(map) => DownloadQueueHandler::download_map(config, map, None).await,
+            DownloadQueueRequest::MapFor(map, targets) => DownloadQueueHandler::download_map(config, map, Some(targets)).await
         }
     }
 
 
-    pub fn start(mut self) -> JoinHandle<()> {
+    pub fn start(self) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let semaphore = Arc::new(Semaphore::new(self.config.config.concurrent_downloads as usize));
+            let semaphore = Arc::new(Semaphore::new(self.config.config.settings.concurrent_downloads as usize));
+            let mut receiver = self.receiver.lock().await;
             loop {
-                if let Some(request) = self.receiver.recv().await {
+                if let Some(request) = receiver.recv().await {
                     match semaphore.clone().acquire_owned().await {
                         Ok(permit) => {
                             let config = self.config.clone();
@@ -171,11 +201,13 @@ impl InstallerQueueRequest {
 }
 
 pub enum InstallerQueueData {
-    Map(BeatSaverMap, MapVersion, Vec<u8>)
+    Map(BeatSaverMap, MapVersion, Arc<MapDownload>)
 }
 
+/// Cloned by the supervisor to restart the queue, all clones share the receiver
+#[derive(Clone)]
 pub struct InstallerQueue {
-    receiver: Receiver<InstallerQueueRequest>,
+    receiver: Arc<Mutex<Receiver<InstallerQueueRequest>>>,
     config: LocalData,
     installer: Installer,
 }
@@ -192,20 +224,34 @@ pub enum InstallerQueueError {
     JoinError(tokio::task::JoinError),
     #[error("Exceeded the maximum amount of retries. Last error: {0}")]
     TriesExceeded(String),
+    #[error(transparent)]
+    QuotaExceeded(#[from] QuotaError),
+}
+
+impl InstallerQueueError {
+    /// Label of the error in the metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InstallerQueueError::JoinError(_) => "join",
+            InstallerQueueError::TriesExceeded(_) => "tries_exceeded",
+            InstallerQueueError::QuotaExceeded(_) => "quota_exceeded",
+        }
+    }
 }
 
 impl InstallerQueue {
     pub fn new(receiver: Receiver<InstallerQueueRequest>, config: LocalData) -> InstallerQueue {
         InstallerQueue {
-            receiver,
+            receiver: Arc::new(Mutex::new(receiver)),
             installer: Installer::from(config.config.clone()),
             config,
         }
     }
 
-    async fn install_map(&self, map: BeatSaverMap, version: MapVersion, data: Vec<u8>,
+    async fn install_map(&self, map: BeatSaverMap, version: MapVersion, data: Arc<MapDownload>,
                          response: tokio::sync::oneshot::Sender<InstallerQueueResult>) {
         if self.config.map_index.lock().await
+            .maps
             .iter()
             .any(|map_data| map_data.has_hash(version.hash.as_str())) {
             response.send(InstallerQueueResult::AlreadyInstalled(map, version)).ok();
@@ -213,7 +259,13 @@ impl InstallerQueue {
         }
         match self.installer.clone() {
             Installer::PC(pc) => {
-                pc.install_map(map.clone(), data.as_ref());
+                let required = crate::installer::unpacked_size(data.path());
+                if let Err(err) = crate::quota::ensure_space(&self.config, required).await {
+                    error!("Cannot install map {}: {}", map.id, err);
+                    response.send(InstallerQueueResult::Error(map, version, err.into())).ok();
+                    return;
+                }
+                pc.install_map(map.clone(), data.path());
                 info!("PC install task succeeded!");
                 if response.send(InstallerQueueResult::Success(map, version)).is_err() {
                     error!("Error when sending result");
@@ -224,7 +276,7 @@ impl InstallerQueue {
                 let mut latest_error = None;
                 let mut success = false;
                 for _ in 0..10 {
-                    match quest.install_map(version.clone(), data.clone()) {
+                    match quest.install_map(version.clone(), data.path()) {
                         Ok(eventual_handle) => {
                             if let Some(handle) = eventual_handle {
                                 match handle.await {
@@ -261,6 +313,11 @@ impl InstallerQueue {
                     }
                 }
                 if success {
+                    // Quest map folders can't be indexed yet, so remember the maps installed by the daemon
+                    let path = std::path::PathBuf::from(format!("custom_level_{}", version.hash.to_uppercase()));
+                    self.config.map_index.lock().await.maps
+                        .push(MapData::Valid(MapMetadata::new(path, version.hash.clone(), &map, None)));
+                    self.config.rewrite_map_index().await;
                     if response.send(InstallerQueueResult::Success(map, version)).is_err() {
                         error!("Error when sending result");
                     }
@@ -280,13 +337,12 @@ impl InstallerQueue {
         }
     }
 
-    pub fn start(mut self) -> JoinHandle<()> {
+    pub fn start(self) -> JoinHandle<()> {
         tokio::spawn(async move {
-            loop {
-                if let Some(request) = self.receiver.recv().await {
-                    match request.data {
-                        InstallerQueueData::Map(map, version, data) => self.install_map(map, version, data, request.channel).await
-                    }
+            let mut receiver = self.receiver.lock().await;
+            while let Some(request) = receiver.recv().await {
+                match request.data {
+                    InstallerQueueData::Map(map, version, data) => self.install_map(map, version, data, request.channel).await
                 }
             }
         })
@@ -0,0 +1,213 @@
+use crate::config::{LocalData, MapData, AuditLogAction};
+use crate::websocket_handler::InstallType;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use log::{debug, info, warn};
+use thiserror::Error;
+use uuid::Uuid;
+use yaml_rust::Yaml;
+
+/// Storage limits of a PC installation, nothing is limited by default
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaConfig {
+    /// Maximum size of all indexed maps in bytes
+    #[serde(default)]
+    pub max_library_size: Option<u64>,
+    #[serde(default)]
+    pub max_maps: Option<usize>,
+    /// Bytes which have to stay free on the drive after installing a map
+    #[serde(default)]
+    pub min_free_space: Option<u64>,
+    /// Moves the least recently added maps which aren't in any playlist to the trash folder instead of
+    /// failing the install. This doesn't free space on the drive, so `minFreeSpace` is never evicted for.
+    #[serde(default)]
+    pub evict: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaUsage {
+    pub installation: Uuid,
+    pub library_size: u64,
+    pub maps: usize,
+    pub free_space: Option<u64>,
+    pub quota: QuotaConfig,
+}
+
+#[derive(Error, Debug)]
+pub enum QuotaError {
+    #[error("Map library would grow to {0} bytes, the limit is {1} bytes")]
+    LibraryFull(u64, u64),
+    #[error("Map library already contains {0} maps, the limit is {1} maps")]
+    TooManyMaps(usize, usize),
+    #[error("Only {0} bytes would be left on the drive, at least {1} bytes have to stay free")]
+    LowDiskSpace(u64, u64),
+}
+
+impl QuotaConfig {
+    pub fn is_limited(&self) -> bool {
+        self.max_library_size.is_some() || self.max_maps.is_some() || self.min_free_space.is_some()
+    }
+
+    pub fn from_yaml(yaml: &Yaml) -> QuotaConfig {
+        let number = |key: &str| yaml[key].as_i64()
+            .filter(|value| *value >= 0)
+            .map(|value| value as u64);
+        QuotaConfig {
+            max_library_size: number("maxLibrarySize"),
+            max_maps: number("maxMaps").map(|value| value as usize),
+            min_free_space: number("minFreeSpace"),
+            evict: yaml["evict"].as_bool().unwrap_or(false),
+        }
+    }
+
+    pub fn to_yaml(&self) -> Yaml {
+        let mut hash = yaml_rust::yaml::Hash::new();
+        let limits = [
+            ("maxLibrarySize", self.max_library_size),
+            ("maxMaps", self.max_maps.map(|value| value as u64)),
+            ("minFreeSpace", self.min_free_space),
+        ];
+        for (key, value) in limits.iter() {
+            if let Some(value) = value {
+                hash.insert(Yaml::String(key.to_string()), Yaml::Integer(*value as i64));
+            }
+        }
+        hash.insert(Yaml::String("evict".to_owned()), Yaml::Boolean(self.evict));
+        Yaml::Hash(hash)
+    }
+
+    fn check(&self, usage: &QuotaUsage, required: u64) -> Result<(), QuotaError> {
+        if let Some(max_library_size) = self.max_library_size {
+            if usage.library_size + required > max_library_size {
+                return Err(QuotaError::LibraryFull(usage.library_size + required, max_library_size));
+            }
+        }
+        if let Some(max_maps) = self.max_maps {
+            if usage.maps >= max_maps {
+                return Err(QuotaError::TooManyMaps(usage.maps, max_maps));
+            }
+        }
+        if let (Some(min_free_space), Some(free_space)) = (self.min_free_space, usage.free_space) {
+            if free_space.saturating_sub(required) < min_free_space {
+                return Err(QuotaError::LowDiskSpace(free_space.saturating_sub(required), min_free_space));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Free bytes on the drive of the given path, asked from the operating system tools
+pub fn free_space(path: &Path) -> Option<u64> {
+    let mut path = path.to_path_buf();
+    while !path.exists() {
+        path = path.parent()?.to_path_buf();
+    }
+    free_space_of_existing(path.as_path())
+}
+
+#[cfg(not(target_family = "windows"))]
+fn free_space_of_existing(path: &Path) -> Option<u64> {
+    let output = std::process::Command::new("df")
+        .arg("-Pk")
+        .arg(path)
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(output.stdout.as_ref()).to_string();
+    let available = stdout.lines().nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse::<u64>()
+        .ok()?;
+    Some(available * 1024)
+}
+
+#[cfg(target_family = "windows")]
+fn free_space_of_existing(path: &Path) -> Option<u64> {
+    let command = format!("([System.IO.DriveInfo]::new('{}')).AvailableFreeSpace",
+                          path.display().to_string().replace('\'', "''"));
+    let output = powershell_script::run(command.as_str(), false).ok()?;
+    output.stdout()?.trim().parse::<u64>().ok()
+}
+
+pub async fn usage(data: &LocalData) -> QuotaUsage {
+    let index = data.map_index.lock().await;
+    let library_size = index.fingerprints.values()
+        .map(|fingerprint| fingerprint.size)
+        .sum();
+    let maps = index.maps.len();
+    drop(index);
+    let free_space = if data.config.install_type == InstallType::PC {
+        free_space(crate::song_core::default_map_folder(&data.config).as_path())
+    } else {
+        None
+    };
+    QuotaUsage {
+        installation: data.config.id,
+        library_size,
+        maps,
+        free_space,
+        quota: data.config.quota.clone(),
+    }
+}
+
+/// Evicts the least recently added map which can be downloaded again and isn't part of a playlist
+/// or a WIP folder, by moving it to the trash folder
+async fn evict_one(data: &LocalData) -> Option<PathBuf> {
+    let install_location = data.config.install_location.as_str();
+    let playlist_hashes = crate::playlists::playlist_hashes(install_location);
+    let wip_folders = crate::song_core::map_folders(install_location).into_iter()
+        .filter(|folder| folder.wip)
+        .map(|folder| folder.path)
+        .collect::<Vec<PathBuf>>();
+    let index = data.map_index.lock().await;
+    let candidate = index.maps.iter()
+        .filter_map(|map| match map {
+            MapData::Valid(meta) if !playlist_hashes.contains(&meta.hash.to_lowercase()) => Some(meta),
+            _ => None
+        })
+        .filter(|meta| !wip_folders.iter().any(|folder| meta.path.starts_with(folder)))
+        .min_by_key(|meta| index.fingerprints.get(&meta.path)
+            .map(|fingerprint| fingerprint.modified)
+            .unwrap_or(0))
+        .map(|meta| (meta.path.clone(), meta.hash.clone()));
+    drop(index);
+    let (path, hash) = candidate?;
+    let trash = crate::song_core::trash_folder(install_location);
+    match crate::song_core::move_map_folder(path.as_path(), trash.as_path()) {
+        Ok(moved) => {
+            info!("Evicted map {} to {}", path.display(), moved.display());
+            data.audit_log_entry(AuditLogAction::MapDelete(hash)).await;
+            data.remove_from_index(std::slice::from_ref(&path)).await;
+            Some(path)
+        }
+        Err(err) => {
+            warn!("Cannot evict map {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Makes sure a map of the given size can be installed, evicting maps if the installation allows it
+pub async fn ensure_space(data: &LocalData, required: u64) -> Result<Vec<PathBuf>, QuotaError> {
+    let quota = &data.config.quota;
+    let mut evicted = Vec::new();
+    if data.config.install_type != InstallType::PC || !quota.is_limited() {
+        return Ok(evicted);
+    }
+    loop {
+        let usage = usage(data).await;
+        debug!("Library of {} uses {} bytes in {} maps", data.config.id, usage.library_size, usage.maps);
+        match quota.check(&usage, required) {
+            Ok(_) => return Ok(evicted),
+            // evicted maps stay on the drive, so they can't make room for minFreeSpace
+            Err(err @ QuotaError::LowDiskSpace(_, _)) => return Err(err),
+            Err(err) if quota.evict => match evict_one(data).await {
+                Some(path) => evicted.push(path),
+                None => return Err(err)
+            },
+            Err(err) => return Err(err)
+        }
+    }
+}
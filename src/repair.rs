@@ -0,0 +1,245 @@
+use crate::config::{LocalData, MapData, AuditLogAction};
+use crate::map_index::IndexError;
+use crate::beatsaver::{self, BeatSaverMap, MapVersion};
+use crate::queue_handler::{InstallerQueueRequest, InstallerQueueData, InstallerQueueResult};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use log::{info, warn, error};
+use uuid::Uuid;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum InvalidReason {
+    MissingInfoDat,
+    BadJson(String),
+    InvalidInfoDat,
+    MissingDifficulty(String),
+    Unreadable(String),
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum RepairAction {
+    /// The folder was re-downloaded from BeatSaver, the broken one got quarantined
+    Reinstalled(String),
+    Quarantined(PathBuf),
+    /// The folder is a valid map by now and got re-indexed
+    Recovered,
+    /// The folder doesn't exist anymore
+    Removed,
+    Failed(String),
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepairEntry {
+    pub path: PathBuf,
+    pub reason: Option<InvalidReason>,
+    pub action: RepairAction,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepairReport {
+    pub installation: Uuid,
+    pub entries: Vec<RepairEntry>,
+}
+
+pub fn classify(error: &IndexError) -> InvalidReason {
+    match error {
+        IndexError::NotAMap(_, _) => InvalidReason::MissingInfoDat,
+        IndexError::MapJsonError(err, _) => InvalidReason::BadJson(err.to_string()),
+        IndexError::InvalidMapInfoDat(_) => InvalidReason::InvalidInfoDat,
+        IndexError::InvalidDifficulty(_, path) => InvalidReason::MissingDifficulty(path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()),
+        err => InvalidReason::Unreadable(err.to_string())
+    }
+}
+
+/// Infers a map hash or a BeatSaver key from a folder name like `1a2b (Song - Mapper)` or `custom_level_<hash>`
+fn infer_map(path: &Path) -> Option<MapReference> {
+    let name = path.file_name()?.to_string_lossy().to_string();
+    let hash = name.split(|c: char| !c.is_ascii_hexdigit())
+        .find(|part| part.len() == 40);
+    if let Some(hash) = hash {
+        return Some(MapReference::Hash(hash.to_lowercase()));
+    }
+    let key = name.split(' ').next()?;
+    if !key.is_empty() && key.len() <= 8 && key.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(MapReference::Key(key.to_lowercase()))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum MapReference {
+    Hash(String),
+    Key(String),
+}
+
+/// State of an invalid map folder before anything gets moved or downloaded
+#[derive(Debug, PartialEq)]
+enum Diagnosis {
+    Removed,
+    Recovered,
+    Broken(InvalidReason, Option<MapReference>),
+}
+
+fn diagnose(path: &Path) -> Diagnosis {
+    if !path.exists() {
+        return Diagnosis::Removed;
+    }
+    match crate::map_index::generate_hash(path.to_path_buf()) {
+        Ok(_) => Diagnosis::Recovered,
+        Err(err) => Diagnosis::Broken(classify(&err), infer_map(path))
+    }
+}
+
+async fn resolve_reference(reference: &MapReference) -> Option<(BeatSaverMap, MapVersion)> {
+    match reference {
+        MapReference::Hash(hash) => {
+            let map = beatsaver::resolve_map_by_hash(hash.as_str()).await.ok()?;
+            let version = map.versions.iter()
+                .find(|version| version.hash.eq_ignore_ascii_case(hash.as_str()))
+                .cloned()
+                .or_else(|| beatsaver::find_latest_version(&map))?;
+            Some((map, version))
+        }
+        MapReference::Key(key) => {
+            let map = beatsaver::resolve_map_by_id(key.as_str()).await.ok()?;
+            let version = beatsaver::find_latest_version(&map)?;
+            Some((map, version))
+        }
+    }
+}
+
+async fn reinstall(local_data: &LocalData, map: BeatSaverMap, version: MapVersion) -> Result<String, String> {
+    let download = beatsaver::download_zip(&version).await
+        .map_err(|err| err.to_string())?;
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    local_data.installer_queue
+        .send(InstallerQueueRequest::create(tx, InstallerQueueData::Map(map, version, Arc::new(download))))
+        .await
+        .map_err(|err| err.to_string())?;
+    match rx.await {
+        Ok(InstallerQueueResult::Success(map, _)) => Ok(map.id),
+        Ok(InstallerQueueResult::AlreadyInstalled(map, _)) => Ok(map.id),
+        Ok(InstallerQueueResult::Error(_, _, err)) => Err(err.to_string()),
+        Err(err) => Err(err.to_string())
+    }
+}
+
+/// Repairs every invalid map folder of an installation: maps which can be identified get re-downloaded,
+/// every other broken folder is moved into the quarantine folder
+pub async fn repair_invalid_maps(local_data: &LocalData) -> RepairReport {
+    let invalid = local_data.map_index.lock().await.maps.iter()
+        .filter_map(|map| match map {
+            MapData::Invalid(path) => Some(path.clone()),
+            _ => None
+        })
+        .collect::<Vec<PathBuf>>();
+    info!("Repairing {} invalid maps", invalid.len());
+    let quarantine = crate::song_core::quarantine_folder(local_data.config.install_location.as_str());
+    let mut entries = Vec::new();
+    let mut removed = Vec::new();
+    for path in invalid {
+        let (reason, reference) = match diagnose(path.as_path()) {
+            Diagnosis::Removed => {
+                removed.push(path.clone());
+                entries.push(RepairEntry {
+                    path,
+                    reason: None,
+                    action: RepairAction::Removed,
+                });
+                continue;
+            }
+            Diagnosis::Recovered => {
+                removed.push(path.clone());
+                entries.push(RepairEntry {
+                    path,
+                    reason: None,
+                    action: RepairAction::Recovered,
+                });
+                continue;
+            }
+            Diagnosis::Broken(reason, reference) => (reason, reference)
+        };
+        let resolved = match reference {
+            Some(reference) => resolve_reference(&reference).await,
+            None => None
+        };
+        let action = match crate::song_core::move_map_folder(path.as_path(), quarantine.as_path()) {
+            Ok(target) => {
+                removed.push(path.clone());
+                match resolved {
+                    Some((map, version)) => {
+                        info!("Re-downloading broken map {} ({})", map.id.as_str(), path.display());
+                        match reinstall(local_data, map, version).await {
+                            Ok(id) => {
+                                local_data.audit_log_entry(AuditLogAction::MapInstall(id.clone())).await;
+                                RepairAction::Reinstalled(id)
+                            }
+                            Err(err) => {
+                                warn!("Re-download of {} failed, keeping it quarantined: {}", path.display(), err);
+                                RepairAction::Quarantined(target)
+                            }
+                        }
+                    }
+                    None => {
+                        info!("Quarantined broken map {} to {}", path.display(), target.display());
+                        RepairAction::Quarantined(target)
+                    }
+                }
+            }
+            Err(err) => {
+                error!("Cannot quarantine {}: {}", path.display(), err);
+                RepairAction::Failed(err.to_string())
+            }
+        };
+        entries.push(RepairEntry {
+            path,
+            reason: Some(reason),
+            action,
+        });
+    }
+    local_data.remove_from_index(removed.as_slice()).await;
+    if entries.iter().any(|entry| matches!(entry.action, RepairAction::Recovered)) {
+        local_data.clone().update_map_index(false).await;
+    }
+    RepairReport {
+        installation: local_data.config.id,
+        entries,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarantines_map_with_missing_difficulty_for_redownload() {
+        let root = std::env::temp_dir().join(format!("aiosaber-repair-{}", std::process::id()));
+        let map = root.join("CustomLevels").join("1a2b (Fixture Song - Fixture Mapper)");
+        std::fs::create_dir_all(&map).unwrap();
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join("maps").join("v2");
+        for entry in std::fs::read_dir(fixture).unwrap() {
+            let file = entry.unwrap().path();
+            std::fs::copy(&file, map.join(file.file_name().unwrap())).unwrap();
+        }
+        std::fs::remove_file(map.join("ExpertStandard.dat")).unwrap();
+
+        assert_eq!(diagnose(map.as_path()), Diagnosis::Broken(
+            InvalidReason::MissingDifficulty("ExpertStandard.dat".to_string()),
+            Some(MapReference::Key("1a2b".to_string()))));
+        let quarantine = root.join("Quarantine");
+        let target = crate::song_core::move_map_folder(map.as_path(), quarantine.as_path()).unwrap();
+        assert!(!map.exists());
+        assert_eq!(target, quarantine.join("1a2b (Fixture Song - Fixture Mapper)"));
+        assert!(target.join("info.dat").is_file());
+        assert_eq!(diagnose(map.as_path()), Diagnosis::Removed);
+        std::fs::remove_dir_all(root).ok();
+    }
+}
@@ -0,0 +1,98 @@
+use crate::beatsaver::BeatSaverMap;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use yaml_rust::Yaml;
+
+/// Decides which maps an installation receives. The default accepts every map.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingRules {
+    /// Only accept maps from requests which explicitly target this installation
+    #[serde(default)]
+    pub requested_only: bool,
+    #[serde(default)]
+    pub ranked_only: bool,
+    #[serde(default)]
+    pub exclude_automapper: bool,
+    #[serde(default)]
+    pub bpm_min: Option<f32>,
+    #[serde(default)]
+    pub bpm_max: Option<f32>,
+    /// Song duration limits in seconds
+    #[serde(default)]
+    pub duration_min: Option<u32>,
+    #[serde(default)]
+    pub duration_max: Option<u32>,
+}
+
+#[derive(Error, Debug)]
+pub enum RoutingRejection {
+    #[error("Installation only accepts maps requested for it")]
+    NotRequested,
+    #[error("Installation only accepts ranked maps")]
+    NotRanked,
+    #[error("Installation doesn't accept automapped maps")]
+    Automapper,
+    #[error("BPM {0} is outside of the accepted range")]
+    Bpm(f32),
+    #[error("Duration of {0}s is outside of the accepted range")]
+    Duration(u32),
+}
+
+impl RoutingRules {
+    pub fn check(&self, map: &BeatSaverMap, requested: bool) -> Result<(), RoutingRejection> {
+        if self.requested_only && !requested {
+            return Err(RoutingRejection::NotRequested);
+        }
+        if self.ranked_only && !map.ranked {
+            return Err(RoutingRejection::NotRanked);
+        }
+        if self.exclude_automapper && map.automapper {
+            return Err(RoutingRejection::Automapper);
+        }
+        let bpm = map.metadata.bpm;
+        if self.bpm_min.map(|bpm_min| bpm < bpm_min).unwrap_or(false) ||
+            self.bpm_max.map(|bpm_max| bpm > bpm_max).unwrap_or(false) {
+            return Err(RoutingRejection::Bpm(bpm));
+        }
+        let duration = map.metadata.duration;
+        if self.duration_min.map(|duration_min| duration < duration_min).unwrap_or(false) ||
+            self.duration_max.map(|duration_max| duration > duration_max).unwrap_or(false) {
+            return Err(RoutingRejection::Duration(duration));
+        }
+        Ok(())
+    }
+
+    pub fn from_yaml(yaml: &Yaml) -> RoutingRules {
+        let flag = |key: &str| yaml[key].as_bool().unwrap_or(false);
+        let number = |key: &str| yaml[key].as_f64().or_else(|| yaml[key].as_i64().map(|value| value as f64));
+        RoutingRules {
+            requested_only: flag("requestedOnly"),
+            ranked_only: flag("rankedOnly"),
+            exclude_automapper: flag("excludeAutomapper"),
+            bpm_min: number("bpmMin").map(|value| value as f32),
+            bpm_max: number("bpmMax").map(|value| value as f32),
+            duration_min: number("durationMin").map(|value| value as u32),
+            duration_max: number("durationMax").map(|value| value as u32),
+        }
+    }
+
+    pub fn to_yaml(&self) -> Yaml {
+        let mut hash = yaml_rust::yaml::Hash::new();
+        hash.insert(Yaml::String("requestedOnly".to_owned()), Yaml::Boolean(self.requested_only));
+        hash.insert(Yaml::String("rankedOnly".to_owned()), Yaml::Boolean(self.ranked_only));
+        hash.insert(Yaml::String("excludeAutomapper".to_owned()), Yaml::Boolean(self.exclude_automapper));
+        let limits = [
+            ("bpmMin", self.bpm_min.map(|value| value as f64)),
+            ("bpmMax", self.bpm_max.map(|value| value as f64)),
+            ("durationMin", self.duration_min.map(|value| value as f64)),
+            ("durationMax", self.duration_max.map(|value| value as f64)),
+        ];
+        for (key, value) in limits.iter() {
+            if let Some(value) = value {
+                hash.insert(Yaml::String(key.to_string()), Yaml::Real(value.to_string()));
+            }
+        }
+        Yaml::Hash(hash)
+    }
+}
@@ -0,0 +1,117 @@
+use crate::websocket_handler::{Envelope, WebSocketMessage};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::error::TrySendError;
+use log::{debug, warn};
+use uuid::Uuid;
+
+/// Messages a single client may queue up before further messages to it are dropped
+const SESSION_BUFFER: usize = 256;
+
+/// Event streams which WebSocket clients subscribe to
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum Topic {
+    /// Results of map installs
+    Jobs,
+    /// Installations whose map index changed
+    Index,
+    Logs,
+}
+
+impl Topic {
+    /// Protocol v1 clients always received install results, they get them without subscribing
+    pub fn v1_defaults() -> Vec<Topic> {
+        vec![Topic::Jobs]
+    }
+}
+
+struct Session {
+    sender: tokio::sync::mpsc::Sender<Envelope>,
+    topics: HashSet<Topic>,
+    /// Set while messages are dropped, so the warning is logged once instead of for every
+    /// dropped message, which would otherwise feed the `Logs` topic
+    lagging: AtomicBool,
+}
+
+/// Connected WebSocket clients, each one has its own outbound queue so replies reach only the
+/// requesting client and a slow client cannot hold back the others
+#[derive(Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<Uuid, Session>>>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions::default()
+    }
+
+    pub fn register(&self, topics: Vec<Topic>) -> (Uuid, tokio::sync::mpsc::Receiver<Envelope>) {
+        let (sender, receiver) = tokio::sync::mpsc::channel(SESSION_BUFFER);
+        let id = Uuid::new_v4();
+        self.sessions.lock().unwrap().insert(id, Session {
+            sender,
+            topics: topics.into_iter().collect(),
+            lagging: AtomicBool::new(false),
+        });
+        debug!("WebSocket session {} registered", id);
+        (id, receiver)
+    }
+
+    pub fn unregister(&self, id: &Uuid) {
+        self.sessions.lock().unwrap().remove(id);
+        debug!("WebSocket session {} closed", id);
+    }
+
+    /// Sends a message to one client, returns false if it is gone
+    pub fn send(&self, id: &Uuid, envelope: Envelope) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some(session) => Sessions::deliver(id, session, envelope),
+            None => false
+        }
+    }
+
+    /// Sends an event to every client subscribed to the topic
+    pub fn publish(&self, topic: Topic, message: WebSocketMessage) {
+        let sessions = self.sessions.lock().unwrap();
+        for (id, session) in sessions.iter().filter(|(_, session)| session.topics.contains(&topic)) {
+            Sessions::deliver(id, session, Envelope::event(message.clone()));
+        }
+    }
+
+    /// Changes the subscriptions of a client and returns the topics it is subscribed to now
+    pub fn subscribe(&self, id: &Uuid, topics: &[Topic], subscribed: bool) -> Vec<Topic> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(id) {
+            Some(session) => {
+                for topic in topics {
+                    if subscribed {
+                        session.topics.insert(*topic);
+                    } else {
+                        session.topics.remove(topic);
+                    }
+                }
+                session.topics.iter().copied().collect()
+            }
+            None => Vec::new()
+        }
+    }
+
+    fn deliver(id: &Uuid, session: &Session, envelope: Envelope) -> bool {
+        match session.sender.try_send(envelope) {
+            Ok(_) => {
+                session.lagging.store(false, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                if !session.lagging.swap(true, Ordering::Relaxed) {
+                    warn!("WebSocket session {} is too slow, dropping messages", id);
+                }
+                true
+            }
+            Err(TrySendError::Closed(_)) => false
+        }
+    }
+}
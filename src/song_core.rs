@@ -0,0 +1,164 @@
+use crate::websocket_handler::ConfigData;
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+
+/// A map folder of a PC installation, either one of the default ones or defined in SongCore's folders.xml
+#[derive(Clone, Debug)]
+pub struct MapFolder {
+    pub name: String,
+    pub path: PathBuf,
+    pub wip: bool,
+}
+
+pub fn custom_levels_folder(install_location: &str) -> PathBuf {
+    let mut path = PathBuf::from(install_location);
+    path.push("Beat Saber_Data");
+    path.push("CustomLevels");
+    path
+}
+
+pub fn custom_wip_levels_folder(install_location: &str) -> PathBuf {
+    let mut path = PathBuf::from(install_location);
+    path.push("Beat Saber_Data");
+    path.push("CustomWIPLevels");
+    path
+}
+
+/// Returns all map folders of a PC installation: CustomLevels, CustomWIPLevels and
+/// every additional folder defined in UserData/SongCore/folders.xml
+pub fn map_folders(install_location: &str) -> Vec<MapFolder> {
+    let mut folders = vec![
+        MapFolder {
+            name: "Custom Levels".to_owned(),
+            path: custom_levels_folder(install_location),
+            wip: false,
+        },
+        MapFolder {
+            name: "Custom WIP Levels".to_owned(),
+            path: custom_wip_levels_folder(install_location),
+            wip: true,
+        },
+    ];
+    for folder in read_folders_xml(install_location) {
+        if !folders.iter().any(|existing| existing.path.eq(&folder.path)) {
+            folders.push(folder);
+        }
+    }
+    folders
+}
+
+/// The folder new maps get installed into. Falls back to CustomLevels if none is configured.
+pub fn default_map_folder(config: &ConfigData) -> PathBuf {
+    match config.map_folder.as_ref() {
+        Some(folder) => {
+            let path = PathBuf::from(folder);
+            if path.is_absolute() {
+                path
+            } else {
+                let mut full_path = PathBuf::from(config.install_location.clone());
+                full_path.push(path);
+                full_path
+            }
+        }
+        None => custom_levels_folder(config.install_location.as_str())
+    }
+}
+
+fn read_folders_xml(install_location: &str) -> Vec<MapFolder> {
+    let mut path = PathBuf::from(install_location);
+    path.push("UserData");
+    path.push("SongCore");
+    path.push("folders.xml");
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            debug!("No SongCore folders at {}: {}", path.display(), err);
+            return Vec::new();
+        }
+    };
+    match roxmltree::Document::parse(contents.as_str()) {
+        Ok(document) => {
+            document.descendants()
+                .filter(|node| node.has_tag_name("folder"))
+                .filter_map(|node| {
+                    let child_text = |name: &str| node.children()
+                        .find(|child| child.has_tag_name(name))
+                        .and_then(|child| child.text())
+                        .map(|text| text.trim().to_string());
+                    let folder_path = child_text("Path")?;
+                    let mut folder_path = PathBuf::from(folder_path);
+                    if folder_path.is_relative() {
+                        let mut full_path = PathBuf::from(install_location);
+                        full_path.push(folder_path);
+                        folder_path = full_path;
+                    }
+                    Some(MapFolder {
+                        name: child_text("Name").unwrap_or_else(|| folder_path.display().to_string()),
+                        path: folder_path,
+                        wip: child_text("WIP")
+                            .map(|wip| wip.eq_ignore_ascii_case("true"))
+                            .unwrap_or(false),
+                    })
+                })
+                .collect()
+        }
+        Err(err) => {
+            warn!("Invalid SongCore folders.xml {}: {}", path.display(), err);
+            Vec::new()
+        }
+    }
+}
+
+/// Folder of a PC installation where AIOSaber moves removed maps to, outside of any map folder
+pub fn trash_folder(install_location: &str) -> PathBuf {
+    let mut path = PathBuf::from(install_location);
+    path.push("UserData");
+    path.push("AIOSaber");
+    path.push("Trash");
+    path
+}
+
+/// Folder of a PC installation where broken maps are moved to, so SongCore doesn't try to load them
+pub fn quarantine_folder(install_location: &str) -> PathBuf {
+    let mut path = PathBuf::from(install_location);
+    path.push("UserData");
+    path.push("AIOSaber");
+    path.push("Quarantine");
+    path
+}
+
+/// Moves a map folder into the target directory, copying it if it lives on another drive
+pub fn move_map_folder(path: &Path, target_dir: &Path) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(target_dir)?;
+    let name = path.file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Map folder has no name"))?;
+    let mut target = target_dir.to_path_buf();
+    target.push(name);
+    let mut counter = 1;
+    while target.exists() {
+        let mut numbered_name = name.to_os_string();
+        numbered_name.push(format!(" ({})", counter));
+        target.set_file_name(numbered_name);
+        counter += 1;
+    }
+    if std::fs::rename(path, &target).is_err() {
+        copy_dir(path, &target)?;
+        std::fs::remove_dir_all(path)?;
+    }
+    Ok(target)
+}
+
+fn copy_dir(source: &Path, target: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(target)?;
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let mut entry_target = target.to_path_buf();
+        entry_target.push(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(entry.path().as_path(), entry_target.as_path())?;
+        } else {
+            std::fs::copy(entry.path(), entry_target)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use crate::config::DaemonConfig;
+use crate::supervisor::{TaskState, TaskStatus};
+use crate::websocket_handler::InstallType;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+lazy_static! {
+    static ref HEALTH: Mutex<HashMap<Uuid, InstallationHealth>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Default)]
+struct InstallationHealth {
+    last_index: Option<DateTime<Utc>>,
+    last_error: Option<ErrorRecord>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ErrorRecord {
+    pub time: DateTime<Utc>,
+    pub message: String,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallationStatus {
+    pub id: Uuid,
+    pub install_type: InstallType,
+    pub install_location: String,
+    pub healthy: bool,
+    /// None for Quest installations, which have no watcher
+    pub watcher: Option<TaskStatus>,
+    pub installer_queue: Option<TaskStatus>,
+    /// Maps waiting for the installer queue
+    pub queue_depth: usize,
+    pub maps: usize,
+    /// Last time the map index was written
+    pub last_index: Option<DateTime<Utc>>,
+    pub last_error: Option<ErrorRecord>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DaemonStatus {
+    pub version: String,
+    pub healthy: bool,
+    /// Tasks of the daemon itself, installation tasks are listed with their installation
+    pub tasks: Vec<TaskStatus>,
+    pub installations: Vec<InstallationStatus>,
+    /// Problems of daemon-config.yaml, it isn't applied or overwritten until they are fixed
+    pub config_errors: Vec<String>,
+}
+
+pub fn record_index(installation: &Uuid) {
+    HEALTH.lock().unwrap().entry(*installation).or_default().last_index = Some(Utc::now());
+}
+
+pub fn record_error(installation: &Uuid, message: String) {
+    HEALTH.lock().unwrap().entry(*installation).or_default().last_error = Some(ErrorRecord {
+        time: Utc::now(),
+        message,
+    });
+}
+
+fn is_running(task: &Option<TaskStatus>) -> bool {
+    task.as_ref().map(|task| task.state == TaskState::Running).unwrap_or(false)
+}
+
+pub async fn status(config: &DaemonConfig, version: String) -> DaemonStatus {
+    let tasks = crate::supervisor::tasks();
+    let find_task = |name: String| tasks.iter().find(|task| task.name.eq(&name)).cloned();
+    let mut installations = Vec::new();
+    for data in config.get_data().await {
+        let id = data.config.id;
+        let health = HEALTH.lock().unwrap().get(&id).cloned().unwrap_or_default();
+        let watcher = if data.config.install_type == InstallType::PC {
+            find_task(crate::supervisor::watcher_task(&id))
+        } else {
+            None
+        };
+        let installer_queue = find_task(crate::supervisor::installer_queue_task(&id));
+        let healthy = is_running(&installer_queue) &&
+            (data.config.install_type != InstallType::PC || is_running(&watcher));
+        installations.push(InstallationStatus {
+            id,
+            install_type: data.config.install_type.clone(),
+            install_location: data.config.install_location.clone(),
+            healthy,
+            watcher,
+            installer_queue,
+            queue_depth: crate::config::INSTALLER_QUEUE_SIZE - data.installer_queue.capacity(),
+            maps: data.map_index.lock().await.maps.len(),
+            last_index: health.last_index,
+            last_error: health.last_error,
+        });
+    }
+    let tasks: Vec<TaskStatus> = tasks.into_iter()
+        .filter(|task| task.installation.is_none())
+        .collect();
+    let config_errors = crate::config::config_file_errors();
+    DaemonStatus {
+        version,
+        healthy: tasks.iter().all(|task| task.state == TaskState::Running) &&
+            installations.iter().all(|installation| installation.healthy) &&
+            config_errors.is_empty(),
+        tasks,
+        installations,
+        config_errors,
+    }
+}
@@ -0,0 +1,169 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use log::{info, error};
+use uuid::Uuid;
+
+const MIN_BACKOFF_SECONDS: u64 = 1;
+const MAX_BACKOFF_SECONDS: u64 = 300;
+/// A task which ran this long before failing starts over with the shortest backoff
+const STABLE_AFTER_SECONDS: i64 = 600;
+
+lazy_static! {
+    static ref TASKS: Mutex<HashMap<String, SupervisedTask>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+pub enum TaskState {
+    Running,
+    /// Failed and waiting for its backoff before it starts again
+    Restarting,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskStatus {
+    pub name: String,
+    pub installation: Option<Uuid>,
+    pub state: TaskState,
+    pub started: DateTime<Utc>,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    pub last_failure: Option<DateTime<Utc>>,
+}
+
+struct SupervisedTask {
+    /// Distinguishes a task from the one it replaced under the same name
+    generation: Uuid,
+    status: TaskStatus,
+    stop: Arc<Notify>,
+}
+
+fn update(name: &str, generation: &Uuid, change: impl FnOnce(&mut TaskStatus)) {
+    if let Some(task) = TASKS.lock().unwrap().get_mut(name) {
+        if task.generation.eq(generation) {
+            change(&mut task.status);
+        }
+    }
+}
+
+fn remove(name: &str, generation: &Uuid) {
+    let mut tasks = TASKS.lock().unwrap();
+    if tasks.get(name).map(|task| task.generation.eq(generation)).unwrap_or(false) {
+        tasks.remove(name);
+    }
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Task panicked".to_string()
+    }
+}
+
+fn backoff(failures: u32) -> u64 {
+    (MIN_BACKOFF_SECONDS << failures.min(16)).min(MAX_BACKOFF_SECONDS)
+}
+
+/// Runs a long living background task and starts it again with exponential backoff whenever it
+/// exits, panics or cannot be started. A task with the same name is stopped first.
+/// The returned handle only completes once the task was stopped.
+pub fn supervise<F>(name: String, installation: Option<Uuid>, start: F) -> JoinHandle<()>
+    where F: Fn() -> Result<JoinHandle<()>, String> + Send + 'static {
+    let generation = Uuid::new_v4();
+    let stop = Arc::new(Notify::new());
+    let replaced = TASKS.lock().unwrap().insert(name.clone(), SupervisedTask {
+        generation,
+        status: TaskStatus {
+            name: name.clone(),
+            installation,
+            state: TaskState::Running,
+            started: Utc::now(),
+            restarts: 0,
+            last_error: None,
+            last_failure: None,
+        },
+        stop: stop.clone(),
+    });
+    if let Some(replaced) = replaced {
+        replaced.stop.notify_one();
+    }
+    tokio::spawn(async move {
+        let mut failures = 0;
+        loop {
+            let started = Utc::now();
+            update(name.as_str(), &generation, |status| {
+                status.state = TaskState::Running;
+                status.started = started;
+            });
+            let error = match start() {
+                Ok(mut handle) => tokio::select! {
+                    result = &mut handle => match result {
+                        Ok(_) => "Task exited".to_string(),
+                        Err(err) if err.is_panic() => panic_message(err.into_panic()),
+                        Err(err) => err.to_string()
+                    },
+                    _ = stop.notified() => {
+                        handle.abort();
+                        info!("Stopped {}", name);
+                        remove(name.as_str(), &generation);
+                        return;
+                    }
+                },
+                Err(err) => err
+            };
+            if (Utc::now() - started).num_seconds() >= STABLE_AFTER_SECONDS {
+                failures = 0;
+            }
+            let delay = backoff(failures);
+            failures += 1;
+            error!("{} failed: {}. Restarting in {}s", name, error, delay);
+            update(name.as_str(), &generation, |status| {
+                status.state = TaskState::Restarting;
+                status.restarts += 1;
+                status.last_error = Some(error);
+                status.last_failure = Some(Utc::now());
+            });
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_secs(delay)) => {}
+                _ = stop.notified() => {
+                    info!("Stopped {}", name);
+                    remove(name.as_str(), &generation);
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// Stops all tasks of an installation, e.g. after it was removed
+pub fn stop_installation(installation: &Uuid) {
+    for task in TASKS.lock().unwrap().values() {
+        if task.status.installation.as_ref() == Some(installation) {
+            task.stop.notify_one();
+        }
+    }
+}
+
+pub fn tasks() -> Vec<TaskStatus> {
+    let mut tasks: Vec<TaskStatus> = TASKS.lock().unwrap().values()
+        .map(|task| task.status.clone())
+        .collect();
+    tasks.sort_by(|a, b| a.name.cmp(&b.name));
+    tasks
+}
+
+pub fn installer_queue_task(installation: &Uuid) -> String {
+    format!("installer-queue:{}", installation)
+}
+
+pub fn watcher_task(installation: &Uuid) -> String {
+    format!("watcher:{}", installation)
+}
@@ -0,0 +1,444 @@
+use crate::config::{DaemonConfig, LocalData, MapData, MapIndex, AuditLogAction};
+use crate::websocket_handler::InstallType;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use log::{debug, info, warn, error};
+use thiserror::Error;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+
+/// Time to wait for more index changes before mirroring, so a batch of installs only triggers one sync
+const MIRROR_DEBOUNCE_SECONDS: u64 = 10;
+/// Share of the target's maps a sync may delete without the confirmation of a dry run
+const MAX_UNCONFIRMED_DELETE_PERCENT: usize = 10;
+
+lazy_static! {
+    /// Installations and map keys queued by syncs whose install hasn't finished yet
+    static ref PENDING: Mutex<HashSet<(Uuid, String)>> = Mutex::new(HashSet::new());
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum SyncMode {
+    /// Makes the target match the source
+    OneWay,
+    /// Installs the maps missing on either side, nothing gets deleted
+    TwoWay,
+}
+
+/// Keeps a target installation in sync with the installation it is configured on, whenever its map index changes
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MirrorConfig {
+    pub target: Uuid,
+    pub mode: SyncMode,
+    #[serde(default)]
+    pub delete: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncRequest {
+    pub source: Uuid,
+    pub target: Uuid,
+    pub mode: SyncMode,
+    /// Deletes maps from the target which aren't installed on the source, only used for one-way syncs.
+    /// Maps in WIP folders and maps unknown to BeatSaver are kept
+    #[serde(default)]
+    pub delete: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+    /// `deleteConfirmation` of a dry run, required to delete more than a tenth of the target's maps
+    #[serde(default)]
+    pub confirm_deletes: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncMap {
+    pub hash: String,
+    pub key: Option<String>,
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncDiff {
+    pub source: Uuid,
+    pub target: Uuid,
+    pub mode: SyncMode,
+    pub install_on_target: Vec<SyncMap>,
+    pub install_on_source: Vec<SyncMap>,
+    pub delete_on_target: Vec<SyncMap>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedMap {
+    pub installation: Uuid,
+    pub key: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncResult {
+    pub diff: SyncDiff,
+    pub dry_run: bool,
+    pub queued: Vec<QueuedMap>,
+    pub deleted: Vec<PathBuf>,
+    pub errors: Vec<String>,
+    /// Set if the deletes of the diff need to be confirmed by passing it as `confirmDeletes`
+    pub delete_confirmation: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("Unknown installation {0}")]
+    UnknownInstallation(Uuid),
+    #[error("Source and target are the same installation")]
+    SameInstallation,
+}
+
+fn maps_by_hash(index: &MapIndex) -> HashMap<String, SyncMap> {
+    index.maps.iter()
+        .filter_map(|map| match map {
+            MapData::Valid(meta) => Some(SyncMap {
+                hash: meta.hash.to_lowercase(),
+                key: Some(format!("{:x}", meta.id)),
+                path: meta.path.clone(),
+            }),
+            MapData::Unknown(unknown) => Some(SyncMap {
+                hash: unknown.hash.to_lowercase(),
+                key: None,
+                path: unknown.path.clone(),
+            }),
+            MapData::Invalid(_) => None
+        })
+        .map(|map| (map.hash.clone(), map))
+        .collect()
+}
+
+/// Maps a sync may delete from the target: maps outside of its WIP folders which can be downloaded again
+fn deletable_maps(index: &MapIndex, wip_folders: &[PathBuf]) -> HashMap<String, SyncMap> {
+    maps_by_hash(index).into_iter()
+        .filter(|(_, map)| map.key.is_some())
+        .filter(|(_, map)| !wip_folders.iter().any(|folder| map.path.starts_with(folder)))
+        .collect()
+}
+
+fn missing(from: &HashMap<String, SyncMap>, on: &HashMap<String, SyncMap>) -> Vec<SyncMap> {
+    let mut maps = from.iter()
+        .filter(|(hash, _)| !on.contains_key(*hash))
+        .map(|(_, map)| map.clone())
+        .collect::<Vec<SyncMap>>();
+    maps.sort_by(|a, b| a.path.cmp(&b.path));
+    maps
+}
+
+/// Compares the map indexes of two installations by map hash, maps in the WIP folders of the target are never deleted
+pub fn compute_diff(request: &SyncRequest, source: &MapIndex, target: &MapIndex, target_wip_folders: &[PathBuf]) -> SyncDiff {
+    let source_maps = maps_by_hash(source);
+    let target_maps = maps_by_hash(target);
+    let (install_on_source, delete_on_target) = match request.mode {
+        SyncMode::OneWay if request.delete => (Vec::new(), missing(&deletable_maps(target, target_wip_folders), &source_maps)),
+        SyncMode::OneWay => (Vec::new(), Vec::new()),
+        SyncMode::TwoWay => (missing(&target_maps, &source_maps), Vec::new())
+    };
+    SyncDiff {
+        source: request.source,
+        target: request.target,
+        mode: request.mode,
+        install_on_target: missing(&source_maps, &target_maps),
+        install_on_source,
+        delete_on_target,
+    }
+}
+
+/// Identifies the maps a sync would delete, so a confirmation only applies to the diff it was made for
+fn delete_confirmation(diff: &SyncDiff) -> String {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(diff.target.as_bytes());
+    for map in diff.delete_on_target.iter() {
+        hasher.update(map.hash.as_bytes());
+    }
+    hasher.hexdigest()
+}
+
+/// Deletes are only safe if the source index holds every map of the source, otherwise the missing
+/// maps would get deleted from the target
+fn check_deletes(request: &SyncRequest, source: &LocalData, source_index: &MapIndex) -> Result<(), String> {
+    if source.config.install_type != InstallType::PC {
+        return Err(format!("Not deleting maps, the index of {} only holds the maps the daemon installed", request.source));
+    }
+    if source.indexing.load(Ordering::SeqCst) {
+        return Err(format!("Not deleting maps, the map index of {} is being rebuilt", request.source));
+    }
+    if source_index.maps.is_empty() {
+        return Err(format!("Not deleting maps, the map index of {} is empty", request.source));
+    }
+    Ok(())
+}
+
+/// Returns the confirmation needed to delete more than a tenth of the target's maps, unless the request has it
+fn missing_confirmation(request: &SyncRequest, target_index: &MapIndex, diff: &SyncDiff) -> Option<String> {
+    let limit = target_index.maps.len() * MAX_UNCONFIRMED_DELETE_PERCENT / 100;
+    if diff.delete_on_target.len() <= limit {
+        return None;
+    }
+    let confirmation = delete_confirmation(diff);
+    if request.confirm_deletes.as_ref().eq(&Some(&confirmation)) {
+        None
+    } else {
+        Some(confirmation)
+    }
+}
+
+/// Called once a queued map got installed or failed, so syncs may queue it again.
+/// Failures before the map got routed to an installation finish it for all installations.
+pub fn finish_pending(installation: Option<&Uuid>, key: &str) {
+    let key = key.to_lowercase();
+    PENDING.lock().unwrap()
+        .retain(|(pending_installation, pending_key)| pending_key.ne(&key) ||
+            installation.map(|installation| installation.ne(pending_installation)).unwrap_or(false));
+}
+
+async fn queue_installs(config: &DaemonConfig, installation: Uuid, maps: &[SyncMap],
+                        queued: &mut Vec<QueuedMap>, errors: &mut Vec<String>) {
+    let mut keys = HashSet::new();
+    for map in maps {
+        match map.key.as_ref() {
+            Some(key) => {
+                if !keys.insert(key.clone()) {
+                    continue;
+                }
+                if !PENDING.lock().unwrap().insert((installation, key.to_lowercase())) {
+                    debug!("Map {} is already queued for {}", key, installation);
+                    continue;
+                }
+                match config.queue_map_for(key.clone(), vec![installation]).await {
+                    Ok(_) => queued.push(QueuedMap {
+                        installation,
+                        key: key.clone(),
+                    }),
+                    Err(err) => {
+                        finish_pending(Some(&installation), key.as_str());
+                        errors.push(format!("{}: {}", key, err))
+                    }
+                }
+            }
+            None => errors.push(format!("{} is not available on BeatSaver", map.path.display()))
+        }
+    }
+}
+
+async fn delete_maps(target: &LocalData, maps: &[SyncMap], deleted: &mut Vec<PathBuf>, errors: &mut Vec<String>) {
+    if maps.is_empty() {
+        return;
+    }
+    if target.config.install_type != InstallType::PC {
+        errors.push("Deleting maps is only supported on PC installations".to_string());
+        return;
+    }
+    let trash = crate::song_core::trash_folder(target.config.install_location.as_str());
+    for map in maps {
+        match crate::song_core::move_map_folder(map.path.as_path(), trash.as_path()) {
+            Ok(moved) => {
+                info!("Sync moved {} to {}", map.path.display(), moved.display());
+                target.audit_log_entry(AuditLogAction::MapDelete(map.hash.clone())).await;
+                deleted.push(map.path.clone());
+            }
+            Err(err) => {
+                error!("Cannot remove {}: {}", map.path.display(), err);
+                errors.push(format!("{}: {}", map.path.display(), err));
+            }
+        }
+    }
+    target.remove_from_index(deleted.as_slice()).await;
+}
+
+/// Syncs the maps of two installations: missing maps get queued for download, extra maps on the target
+/// are moved to its trash folder if requested. Dry runs only report the difference.
+pub async fn sync_installations(config: &DaemonConfig, request: &SyncRequest) -> Result<SyncResult, SyncError> {
+    if request.source == request.target {
+        return Err(SyncError::SameInstallation);
+    }
+    let source = config.get_local_data(&request.source).await
+        .ok_or(SyncError::UnknownInstallation(request.source))?;
+    let target = config.get_local_data(&request.target).await
+        .ok_or(SyncError::UnknownInstallation(request.target))?;
+    let source_index = source.map_index.lock().await.clone();
+    let target_index = target.map_index.lock().await.clone();
+    let target_wip_folders = crate::song_core::map_folders(target.config.install_location.as_str()).into_iter()
+        .filter(|folder| folder.wip)
+        .map(|folder| folder.path)
+        .collect::<Vec<PathBuf>>();
+    let diff = compute_diff(request, &source_index, &target_index, target_wip_folders.as_slice());
+
+    let mut queued = Vec::new();
+    let mut deleted = Vec::new();
+    let mut errors = Vec::new();
+    let mut delete_confirmation = None;
+    let deletes_allowed = if diff.delete_on_target.is_empty() {
+        false
+    } else if let Err(err) = check_deletes(request, &source, &source_index) {
+        errors.push(err);
+        false
+    } else if let Some(confirmation) = missing_confirmation(request, &target_index, &diff) {
+        errors.push(format!("Not deleting {} of {} maps without the confirmation of a dry run",
+                            diff.delete_on_target.len(), target_index.maps.len()));
+        delete_confirmation = Some(confirmation);
+        false
+    } else {
+        true
+    };
+    if !request.dry_run {
+        queue_installs(config, request.target, diff.install_on_target.as_slice(), &mut queued, &mut errors).await;
+        queue_installs(config, request.source, diff.install_on_source.as_slice(), &mut queued, &mut errors).await;
+        if deletes_allowed {
+            delete_maps(&target, diff.delete_on_target.as_slice(), &mut deleted, &mut errors).await;
+        }
+        info!("Synced {} -> {}: queued {} maps, deleted {} maps", request.source, request.target, queued.len(), deleted.len());
+    }
+    Ok(SyncResult {
+        diff,
+        dry_run: request.dry_run,
+        queued,
+        deleted,
+        errors,
+        delete_confirmation,
+    })
+}
+
+/// Runs the configured mirrors whenever the map index of one of their installations changes
+pub struct MirrorHandler {
+    config: DaemonConfig,
+}
+
+impl MirrorHandler {
+    pub fn new(config: DaemonConfig) -> MirrorHandler {
+        MirrorHandler {
+            config,
+        }
+    }
+
+    async fn mirror(&self, changed: Option<HashSet<Uuid>>) {
+        for data in self.config.get_data().await {
+            let mirror = match data.config.mirror.as_ref() {
+                Some(mirror) => mirror,
+                None => continue
+            };
+            let affected = changed.as_ref()
+                .map(|changed| changed.contains(&data.config.id) ||
+                    (mirror.mode == SyncMode::TwoWay && changed.contains(&mirror.target)))
+                .unwrap_or(true);
+            if !affected {
+                continue;
+            }
+            let request = SyncRequest {
+                source: data.config.id,
+                target: mirror.target,
+                mode: mirror.mode,
+                delete: mirror.delete,
+                dry_run: false,
+                confirm_deletes: None,
+            };
+            match sync_installations(&self.config, &request).await {
+                Ok(result) => {
+                    for err in result.errors {
+                        warn!("Mirror {} -> {}: {}", request.source, request.target, err);
+                    }
+                }
+                Err(err) => error!("Mirror {} -> {} failed: {}", request.source, request.target, err)
+            }
+        }
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut events = crate::config::subscribe_index_events();
+            loop {
+                let mut changed = HashSet::new();
+                match events.recv().await {
+                    Ok(id) => {
+                        changed.insert(id);
+                    }
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(MIRROR_DEBOUNCE_SECONDS)).await;
+                let mut lagged = false;
+                loop {
+                    match events.try_recv() {
+                        Ok(id) => {
+                            changed.insert(id);
+                        }
+                        Err(TryRecvError::Lagged(_)) => lagged = true,
+                        Err(_) => break
+                    }
+                }
+                debug!("Map indexes changed: {:?}", changed);
+                self.mirror(if lagged || changed.is_empty() { None } else { Some(changed) }).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MapMetadata, UnknownMap, UnknownReason};
+
+    fn valid(path: &str, id: u32) -> MapData {
+        MapData::Valid(MapMetadata {
+            path: PathBuf::from(path),
+            hash: format!("{:040x}", id),
+            id,
+            info: None,
+            ranked: false,
+            qualified: false,
+            automapper: false,
+            uploaded: None,
+        })
+    }
+
+    fn index(maps: Vec<MapData>) -> MapIndex {
+        MapIndex {
+            maps,
+            fingerprints: Default::default(),
+        }
+    }
+
+    #[test]
+    fn deletes_only_downloadable_maps_outside_of_wip_folders() {
+        let request = SyncRequest {
+            source: Uuid::nil(),
+            target: Uuid::nil(),
+            mode: SyncMode::OneWay,
+            delete: true,
+            dry_run: true,
+            confirm_deletes: None,
+        };
+        let source = index(vec![valid("Source/CustomLevels/1 (Kept)", 1)]);
+        let target = index(vec![
+            valid("Target/CustomLevels/1 (Kept)", 1),
+            valid("Target/CustomLevels/2 (Extra)", 2),
+            valid("Target/CustomWIPLevels/3 (Wip)", 3),
+            MapData::Unknown(UnknownMap {
+                path: PathBuf::from("Target/CustomLevels/Unknown"),
+                hash: "f".repeat(40),
+                info: None,
+                reason: UnknownReason::NotFound,
+                last_checked: None,
+                attempts: 0,
+            }),
+        ]);
+        let diff = compute_diff(&request, &source, &target, &[PathBuf::from("Target/CustomWIPLevels")]);
+        let deleted = diff.delete_on_target.iter()
+            .map(|map| map.path.clone())
+            .collect::<Vec<PathBuf>>();
+        assert_eq!(deleted, vec![PathBuf::from("Target/CustomLevels/2 (Extra)")]);
+    }
+}
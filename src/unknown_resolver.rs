@@ -0,0 +1,99 @@
+use crate::config::{DaemonConfig, LocalData, MapData, MapMetadata, UnknownMap, UnknownReason};
+use crate::beatsaver;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, info};
+use std::path::PathBuf;
+use tokio::task::JoinHandle;
+
+/// How often the index is scanned for Unknown maps which are due for another lookup
+const CHECK_INTERVAL_SECONDS: u64 = 5 * 60;
+const TRANSIENT_RETRY_MINUTES: i64 = 5;
+const TRANSIENT_RETRY_MAX_MINUTES: i64 = 6 * 60;
+const NOT_FOUND_RETRY_MINUTES: i64 = 6 * 60;
+const NOT_FOUND_RETRY_MAX_MINUTES: i64 = 7 * 24 * 60;
+
+/// Periodically looks up Unknown maps on BeatSaver again and promotes them to Valid once they are found.
+/// Temporary failures are retried quickly, maps missing on BeatSaver only every few hours up to once a week.
+pub struct UnknownResolver {
+    config: DaemonConfig,
+}
+
+impl UnknownResolver {
+    pub fn new(config: DaemonConfig) -> UnknownResolver {
+        UnknownResolver {
+            config,
+        }
+    }
+
+    fn next_check(map: &UnknownMap) -> Option<DateTime<Utc>> {
+        let last_checked = map.last_checked?;
+        let (base, max) = if map.reason.is_transient() {
+            (TRANSIENT_RETRY_MINUTES, TRANSIENT_RETRY_MAX_MINUTES)
+        } else {
+            (NOT_FOUND_RETRY_MINUTES, NOT_FOUND_RETRY_MAX_MINUTES)
+        };
+        let minutes = base.saturating_mul(1i64 << map.attempts.min(16)).min(max);
+        Some(last_checked + Duration::minutes(minutes))
+    }
+
+    fn is_due(map: &UnknownMap, now: DateTime<Utc>) -> bool {
+        UnknownResolver::next_check(map)
+            .map(|next_check| next_check <= now)
+            .unwrap_or(true)
+    }
+
+    async fn resolve_installation(data: &LocalData) {
+        let now = Utc::now();
+        let due = data.map_index.lock().await.maps.iter()
+            .filter_map(|map| match map {
+                MapData::Unknown(unknown) if UnknownResolver::is_due(unknown, now) => Some(unknown.clone()),
+                _ => None
+            })
+            .collect::<Vec<UnknownMap>>();
+        if due.is_empty() {
+            return;
+        }
+        debug!("Re-resolving {} unknown maps of {}", due.len(), data.config.id);
+
+        let mut results: Vec<(PathBuf, String, MapData)> = Vec::new();
+        for unknown in due {
+            let resolved = match beatsaver::resolve_map_by_hash(unknown.hash.as_str()).await {
+                Ok(map) => {
+                    info!("Unknown map {} is now available as {}", unknown.path.display(), map.id);
+                    MapData::Valid(MapMetadata::new(unknown.path.clone(), unknown.hash.clone(), &map, unknown.info.clone()))
+                }
+                Err(error) => MapData::Unknown(UnknownMap {
+                    reason: UnknownReason::from(&error),
+                    last_checked: Some(Utc::now()),
+                    attempts: unknown.attempts.saturating_add(1),
+                    ..unknown.clone()
+                })
+            };
+            results.push((unknown.path, unknown.hash, resolved));
+        }
+
+        let mut index = data.map_index.lock().await;
+        for (path, hash, resolved) in results {
+            // the folder may have been removed or replaced while BeatSaver was queried
+            let entry = index.maps.iter_mut()
+                .find(|map| matches!(map, MapData::Unknown(unknown) if unknown.path.eq(&path) && unknown.hash.eq(&hash)));
+            if let Some(entry) = entry {
+                *entry = resolved;
+            }
+        }
+        drop(index);
+        data.rewrite_map_index().await;
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(CHECK_INTERVAL_SECONDS));
+            loop {
+                interval.tick().await;
+                for data in self.config.get_data().await {
+                    UnknownResolver::resolve_installation(&data).await;
+                }
+            }
+        })
+    }
+}
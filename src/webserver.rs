@@ -5,10 +5,21 @@ use std::process::exit;
 use log::{trace, debug, info, warn, error};
 use tokio::time::Duration;
 use futures_util::{StreamExt, SinkExt, TryFutureExt};
-use crate::websocket_handler::{WebSocketHandler, WebSocketMessage};
-use crate::config::DaemonConfig;
+use crate::websocket_handler::{WebSocketHandler, WebSocketMessage, Envelope, ErrorEnvelope, ErrorCode, Hello, PROTOCOL_VERSION};
+use crate::config::{DaemonConfig, AuditLogAction};
 use warp::http::StatusCode;
+use crate::map_query::MapQuery;
+use crate::duplicates::CleanupPolicy;
+use crate::backup::RestoreRequest;
+use crate::sync::{SyncError, SyncRequest};
+use crate::auth::PairingRequest;
+use crate::mdns::MdnsResponder;
+use crate::sessions::{Sessions, Topic};
+use crate::log_buffer::{LogFilter, WEBSOCKET_SEND_TARGET};
+use serde::Deserialize;
+use uuid::Uuid;
 
+#[derive(Clone)]
 pub struct WebServer {
     version: String,
     config: DaemonConfig,
@@ -23,19 +34,60 @@ impl WebServer {
     }
 
     pub(crate) fn start(self, addr: SocketAddr) -> (JoinHandle<()>, WebSocketHandler) {
-        let (ws_outbound_tx, _) = tokio::sync::broadcast::channel(32);
+        let sessions = Sessions::new();
         let (ws_inbound_tx, ws_inbound_rx) = tokio::sync::mpsc::channel(32);
 
-        let handler = WebSocketHandler::new(ws_outbound_tx.clone(), ws_inbound_rx, self.config.clone());
+        let handler = WebSocketHandler::new(sessions.clone(), ws_inbound_rx, self.config.clone());
+        let web_server = crate::supervisor::supervise("webserver".to_string(), None,
+                                                      move || Ok(self.clone().serve(addr, sessions.clone(), ws_inbound_tx.clone())));
+        (web_server, handler)
+    }
+
+    fn serve(self, addr: SocketAddr, sessions: Sessions,
+             ws_inbound_tx: tokio::sync::mpsc::Sender<(Uuid, warp::ws::Message)>) -> JoinHandle<()> {
         let config = self.config.clone();
-        let web_server = tokio::spawn(async move {
+        let allowed_origins = self.config.settings.allowed_origins();
+        let lan = self.config.settings.lan.clone();
+        tokio::spawn(async move {
             let cors = warp::cors()
                 .allow_methods(vec!["GET", "POST"])
-                .allow_origins(vec!["https://beatsaver.com", "https://scoresaber.com", "https://aiosaber.zerotwo.workers.dev"]);
+                .allow_headers(vec!["authorization", "content-type"])
+                .allow_origins(allowed_origins.iter().map(|origin| origin.as_str()));
+            let authenticated = crate::auth::authenticated(config.clone());
+
+            let shutdown_config = config.clone();
+            let shutdown = warp::path!("admin" / "shutdown")
+                .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::any().map(move || shutdown_config.clone()))
+                .and_then(WebServer::shutdown)
+                .with(cors.clone());
 
-            let shutdown = warp::get()
-                .and(warp::path("shutdown"))
-                .and_then(WebServer::shutdown);
+            let restart_config = config.clone();
+            let restart = warp::path!("admin" / "restart")
+                .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::any().map(move || restart_config.clone()))
+                .and_then(WebServer::restart)
+                .with(cors.clone());
+
+            let reload_config = config.clone();
+            let reload = warp::path!("admin" / "reload-config")
+                .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::any().map(move || reload_config.clone()))
+                .and_then(WebServer::reload_config)
+                .with(cors.clone());
+
+            let reindex_config = config.clone();
+            let reindex = warp::path!("admin" / "reindex" / Uuid)
+                .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::query::<ReindexQuery>())
+                .and(warp::any().map(move || reindex_config.clone()))
+                .and_then(|id, query: ReindexQuery, config| async move {
+                    WebServer::reindex(config, id, query.aggressive).await
+                }).with(cors.clone());
 
             let options = warp::options().map(WebServer::options)
                 .with(cors.clone());
@@ -43,11 +95,142 @@ impl WebServer {
             let queue_config = config.clone();
             let queue_map = warp::path!("queue" / "map" / String)
                 .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::query::<QueueQuery>())
                 .and(warp::any().map(move || queue_config.clone()))
+                .and_then(|id, query: QueueQuery, config| async move {
+                    WebServer::queue_install(config, id, query.installations).await
+                }).with(cors.clone());
+
+            let maps_config = config.clone();
+            let query_maps = warp::path!("installations" / Uuid / "maps")
+                .and(warp::get())
+                .and(authenticated.clone())
+                .and(warp::query::<MapQuery>())
+                .and(warp::any().map(move || maps_config.clone()))
+                .and_then(|id, query, config| async move {
+                    WebServer::query_maps(config, id, query).await
+                }).with(cors.clone());
+
+            let duplicates_config = config.clone();
+            let find_duplicates = warp::path!("installations" / Uuid / "duplicates")
+                .and(warp::get())
+                .and(authenticated.clone())
+                .and(warp::any().map(move || duplicates_config.clone()))
+                .and_then(|id, config| async move {
+                    WebServer::find_duplicates(config, id).await
+                }).with(cors.clone());
+
+            let cleanup_config = config.clone();
+            let cleanup_duplicates = warp::path!("installations" / Uuid / "duplicates" / "cleanup")
+                .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::query::<CleanupQuery>())
+                .and(warp::any().map(move || cleanup_config.clone()))
+                .and_then(|id, query: CleanupQuery, config| async move {
+                    WebServer::cleanup_duplicates(config, id, query.policy).await
+                }).with(cors.clone());
+
+            let repair_config = config.clone();
+            let repair_maps = warp::path!("installations" / Uuid / "repair")
+                .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::any().map(move || repair_config.clone()))
                 .and_then(|id, config| async move {
-                    WebServer::queue_install(config, id).await
+                    WebServer::repair_maps(config, id).await
                 }).with(cors.clone());
 
+            let backup_config = config.clone();
+            let create_backup = warp::path!("installations" / Uuid / "backup")
+                .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::query::<BackupQuery>())
+                .and(warp::any().map(move || backup_config.clone()))
+                .and_then(|id, query: BackupQuery, config| async move {
+                    WebServer::create_backup(config, id, query.archive).await
+                }).with(cors.clone());
+
+            let restore_config = config.clone();
+            let restore_backup = warp::path!("installations" / Uuid / "restore")
+                .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::body::json())
+                .and(warp::any().map(move || restore_config.clone()))
+                .and_then(|id, request, config| async move {
+                    WebServer::restore_backup(config, id, request).await
+                }).with(cors.clone());
+
+            let sync_config = config.clone();
+            let sync_installations = warp::path!("sync")
+                .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::body::json())
+                .and(warp::any().map(move || sync_config.clone()))
+                .and_then(|request, config| async move {
+                    WebServer::sync_installations(config, request).await
+                }).with(cors.clone());
+
+            let usage_config = config.clone();
+            let quota_usage = warp::path!("installations" / Uuid / "usage")
+                .and(warp::get())
+                .and(authenticated.clone())
+                .and(warp::any().map(move || usage_config.clone()))
+                .and_then(|id, config| async move {
+                    WebServer::quota_usage(config, id).await
+                }).with(cors.clone());
+
+            let pairing_config = config.clone();
+            let start_pairing = warp::path!("pair")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and(crate::lan::remote_addr())
+                .and(warp::any().map(move || pairing_config.clone()))
+                .and_then(|request, source, config: DaemonConfig| async move {
+                    WebServer::start_pairing(config, request, source).await
+                }).with(cors.clone());
+
+            let pairing_status_config = config.clone();
+            let pairing_status = warp::path!("pair" / Uuid)
+                .and(warp::get())
+                .and(warp::any().map(move || pairing_status_config.clone()))
+                .and_then(|id, config: DaemonConfig| async move {
+                    WebServer::pairing_status(config, id).await
+                }).with(cors.clone());
+
+            let confirm_config = config.clone();
+            let confirm_pairing = warp::path!("pair" / "confirm" / String)
+                .and(warp::post())
+                .and(authenticated.clone())
+                .and(warp::any().map(move || confirm_config.clone()))
+                .and_then(|code: String, config: DaemonConfig| async move {
+                    WebServer::confirm_pairing(config, code).await
+                }).with(cors.clone());
+
+            let logs = warp::path!("logs")
+                .and(warp::get())
+                .and(authenticated.clone())
+                .and(warp::query::<LogFilter>())
+                .map(|filter: LogFilter| Box::new(warp::reply::json(&filter.records())))
+                .with(cors.clone());
+
+            let status_config = config.clone();
+            let status_version = self.version.clone();
+            let status = warp::path!("status")
+                .and(warp::get())
+                .and(authenticated.clone())
+                .and(warp::any().map(move || status_config.clone()))
+                .and(warp::any().map(move || status_version.clone()))
+                .and_then(WebServer::status)
+                .with(cors.clone());
+
+            let metrics_config = config.clone();
+            let metrics = warp::path!("metrics")
+                .and(warp::get())
+                .and(authenticated.clone())
+                .and(warp::any().map(move || metrics_config.clone()))
+                .and_then(WebServer::metrics)
+                .with(cors.clone());
+
             let version = self.version.clone();
             let version_info = warp::path!("version")
                 .and(warp::get())
@@ -55,37 +238,96 @@ impl WebServer {
                 .map(|version| Ok(Box::new(version)))
                 .with(cors.clone());
 
-            let ws_tx = ws_outbound_tx.clone();
+            let ws_sessions = sessions.clone();
             let ws_inbound_tx = ws_inbound_tx.clone();
             let config = config.clone();
+            let ws_version = self.version.clone();
             let websocket = warp::path("pipe")
                 .and(warp::ws())
-                .and(warp::any().map(move || ws_tx.clone()))
+                .and(warp::any().map(move || ws_sessions.clone()))
                 .and(warp::any().map(move || ws_inbound_tx.clone()))
                 .and(warp::any().map(move || config.clone()))
-                .map(|ws: warp::ws::Ws, tx, inbound_tx, config| {
+                .and(warp::any().map(move || ws_version.clone()))
+                .map(|ws: warp::ws::Ws, sessions, inbound_tx, config, version| {
                     trace!("WebSocket connection created!");
-                    ws.on_upgrade(move |websocket| WebServer::websocket_connected(websocket, tx, inbound_tx, config))
+                    ws.on_upgrade(move |websocket| WebServer::websocket_connected(websocket, sessions, inbound_tx, config, version))
                 }).with(cors);
 
-            warp::serve(
-                options
-                    .or(version_info)
-                    .or(queue_map)
-                    .or(websocket)
-                    .or(shutdown),
-            )
-                .run(addr)
-                .await;
-        });
-        (web_server, handler)
+            let routes = options
+                .or(version_info)
+                .or(queue_map)
+                .or(query_maps)
+                .or(find_duplicates)
+                .or(cleanup_duplicates)
+                .or(repair_maps)
+                .or(create_backup)
+                .or(restore_backup)
+                .or(sync_installations)
+                .or(quota_usage)
+                .or(logs)
+                .or(status)
+                .or(metrics)
+                .or(start_pairing)
+                .or(confirm_pairing)
+                .or(pairing_status)
+                .or(websocket)
+                .or(shutdown)
+                .or(restart)
+                .or(reload)
+                .or(reindex)
+                .recover(crate::auth::handle_rejection);
+
+            // Other devices only reach the daemon over TLS, everything except pairing requires a token.
+            // It runs in this task, so the LAN port is released when the web server restarts.
+            let lan_routes = routes.clone();
+            let lan_version = self.version.clone();
+            let lan_server = async move {
+                if !lan.enabled {
+                    return futures_util::future::pending().await;
+                }
+                match crate::lan::tls_incoming(&lan).await {
+                    Ok(incoming) => {
+                        let server = crate::lan::serve(incoming, warp::service(lan_routes));
+                        let responder = if lan.mdns { MdnsResponder::new(&lan, lan_version) } else { None };
+                        match responder {
+                            Some(responder) => {
+                                tokio::join!(server, responder.advertise());
+                            }
+                            None => server.await
+                        }
+                    }
+                    Err(err) => {
+                        error!("LAN mode is disabled: {}", err);
+                        futures_util::future::pending().await
+                    }
+                }
+            };
+
+            tokio::select! {
+                _val = warp::serve(routes).run(addr) => {}
+                _val = lan_server => {}
+            }
+        })
+    }
+
+    async fn status(config: DaemonConfig, version: String) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        Ok(Box::new(warp::reply::json(&crate::status::status(&config, version).await)))
+    }
+
+    async fn metrics(config: DaemonConfig) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        if !config.settings.metrics {
+            return Ok(Box::new(warp::reply::with_status("Metrics are disabled", StatusCode::NOT_FOUND)));
+        }
+        let metrics = crate::metrics::render(&config).await;
+        Ok(Box::new(warp::reply::with_header(metrics, "Content-Type", "text/plain; version=0.0.4")))
     }
 
     fn options() -> Box<dyn warp::Reply> {
         Box::new("OK")
     }
 
-    async fn shutdown() -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    async fn shutdown(config: DaemonConfig) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        config.audit_log_entry(AuditLogAction::Shutdown).await;
         tokio::spawn(async move {
             tokio::time::sleep(Duration::from_secs(1)).await;
             exit(0);
@@ -93,15 +335,78 @@ impl WebServer {
         Ok(Box::new("OK"))
     }
 
-    async fn queue_install(config: DaemonConfig, id: String) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    /// Starts a new daemon process with the same arguments and exits this one
+    async fn restart(config: DaemonConfig) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        config.audit_log_entry(AuditLogAction::Restart).await;
+        let exe = match std::env::current_exe() {
+            Ok(exe) => exe,
+            Err(err) => {
+                error!("Cannot find daemon executable: {}", err);
+                return Ok(Box::new(warp::reply::with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)));
+            }
+        };
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            match std::process::Command::new(exe)
+                .args(std::env::args().skip(1))
+                .env(crate::RESTARTED_ENV, "1")
+                .spawn() {
+                Ok(_) => {
+                    info!("Restarting daemon...");
+                    exit(0);
+                }
+                Err(err) => error!("Cannot restart daemon: {}", err)
+            }
+        });
+        Ok(Box::new("OK"))
+    }
+
+    async fn reload_config(config: DaemonConfig) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        config.audit_log_entry(AuditLogAction::ReloadConfig).await;
+        match config.reload_from_file().await {
+            Ok(configs) => Ok(Box::new(warp::reply::json(&configs))),
+            Err(errors) => Ok(Box::new(warp::reply::with_status(warp::reply::json(&errors), StatusCode::BAD_REQUEST)))
+        }
+    }
+
+    async fn reindex(config: DaemonConfig, id: Uuid, aggressive: bool) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.get_local_data(&id).await {
+            Some(mut local_data) => {
+                local_data.audit_log_entry(AuditLogAction::Reindex(id)).await;
+                let errors = local_data.update_map_index(aggressive).await
+                    .map(|errors| errors.iter().map(|err| err.to_string()).collect::<Vec<String>>())
+                    .unwrap_or_default();
+                Ok(Box::new(warp::reply::json(&errors)))
+            }
+            None => Ok(Box::new(warp::reply::with_status("Unknown installation", StatusCode::NOT_FOUND)))
+        }
+    }
+
+    async fn queue_install(config: DaemonConfig, id: String, installations: Option<String>) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        let targets = match installations {
+            Some(installations) => match installations.split(',')
+                .map(|installation| Uuid::parse_str(installation.trim()))
+                .collect::<Result<Vec<Uuid>, _>>() {
+                Ok(targets) => Some(targets),
+                Err(_) => return Ok(Box::new(warp::reply::with_status("Invalid installation id", StatusCode::BAD_REQUEST)))
+            },
+            None => None
+        };
         let mut needs_download = false;
         for local_data in config.get_data().await.iter() {
+            if targets.as_ref().map(|targets| !targets.contains(&local_data.config.id)).unwrap_or(false) {
+                continue;
+            }
             let installed = local_data.is_map_installed_by_id(id.as_str()).await;
             if !installed { needs_download = true }
         }
 
         if needs_download {
-            match config.queue_map(id).await {
+            let queued = match targets {
+                Some(targets) => config.queue_map_for(id, targets).await,
+                None => config.queue_map(id).await
+            };
+            match queued {
                 Ok(_) => Ok(Box::new(warp::reply::with_status("", StatusCode::NO_CONTENT))),
                 Err(err) => {
                     error!("An error occurred when trying to submit map into download queue: {}", err);
@@ -113,31 +418,189 @@ impl WebServer {
         }
     }
 
+    async fn query_maps(config: DaemonConfig, id: Uuid, query: MapQuery) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.get_local_data(&id).await {
+            Some(local_data) => {
+                let index = local_data.map_index.lock().await;
+                Ok(Box::new(warp::reply::json(&query.execute(id, &index))))
+            }
+            None => Ok(Box::new(warp::reply::with_status("Unknown installation", StatusCode::NOT_FOUND)))
+        }
+    }
+
+    async fn find_duplicates(config: DaemonConfig, id: Uuid) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.get_local_data(&id).await {
+            Some(local_data) => {
+                let index = local_data.map_index.lock().await;
+                Ok(Box::new(warp::reply::json(&crate::duplicates::find_duplicates(id, &index))))
+            }
+            None => Ok(Box::new(warp::reply::with_status("Unknown installation", StatusCode::NOT_FOUND)))
+        }
+    }
+
+    async fn cleanup_duplicates(config: DaemonConfig, id: Uuid, policy: CleanupPolicy) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.get_local_data(&id).await {
+            Some(local_data) => {
+                info!("Cleaning up duplicates...");
+                Ok(Box::new(warp::reply::json(&crate::duplicates::cleanup_duplicates(&local_data, policy).await)))
+            }
+            None => Ok(Box::new(warp::reply::with_status("Unknown installation", StatusCode::NOT_FOUND)))
+        }
+    }
+
+    async fn repair_maps(config: DaemonConfig, id: Uuid) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.get_local_data(&id).await {
+            Some(local_data) => Ok(Box::new(warp::reply::json(&crate::repair::repair_invalid_maps(&local_data).await))),
+            None => Ok(Box::new(warp::reply::with_status("Unknown installation", StatusCode::NOT_FOUND)))
+        }
+    }
+
+    async fn create_backup(config: DaemonConfig, id: Uuid, archive: bool) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.get_local_data(&id).await {
+            Some(local_data) => match crate::backup::create_backup(&local_data, archive).await {
+                Ok(result) => Ok(Box::new(warp::reply::json(&result))),
+                Err(err) => {
+                    error!("Backup of {} failed: {}", id, err);
+                    Ok(Box::new(warp::reply::with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)))
+                }
+            },
+            None => Ok(Box::new(warp::reply::with_status("Unknown installation", StatusCode::NOT_FOUND)))
+        }
+    }
+
+    async fn restore_backup(config: DaemonConfig, id: Uuid, request: RestoreRequest) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.get_local_data(&id).await {
+            Some(local_data) => match crate::backup::restore_backup(&config, &local_data, &request).await {
+                Ok(result) => Ok(Box::new(warp::reply::json(&result))),
+                Err(err) => {
+                    error!("Restore of {} failed: {}", id, err);
+                    Ok(Box::new(warp::reply::with_status(err.to_string(), StatusCode::BAD_REQUEST)))
+                }
+            },
+            None => Ok(Box::new(warp::reply::with_status("Unknown installation", StatusCode::NOT_FOUND)))
+        }
+    }
+
+    async fn sync_installations(config: DaemonConfig, request: SyncRequest) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match crate::sync::sync_installations(&config, &request).await {
+            Ok(result) => Ok(Box::new(warp::reply::json(&result))),
+            Err(err @ SyncError::UnknownInstallation(_)) => Ok(Box::new(warp::reply::with_status(err.to_string(), StatusCode::NOT_FOUND))),
+            Err(err) => Ok(Box::new(warp::reply::with_status(err.to_string(), StatusCode::BAD_REQUEST)))
+        }
+    }
+
+    async fn quota_usage(config: DaemonConfig, id: Uuid) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.get_local_data(&id).await {
+            Some(local_data) => Ok(Box::new(warp::reply::json(&crate::quota::usage(&local_data).await))),
+            None => Ok(Box::new(warp::reply::with_status("Unknown installation", StatusCode::NOT_FOUND)))
+        }
+    }
+
+    async fn start_pairing(config: DaemonConfig, request: PairingRequest, source: Option<SocketAddr>) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.auth.start_pairing(request, source).await {
+            Ok(started) => Ok(Box::new(warp::reply::json(&started))),
+            Err(err) => Ok(Box::new(warp::reply::with_status(err.to_string(), StatusCode::TOO_MANY_REQUESTS)))
+        }
+    }
+
+    async fn pairing_status(config: DaemonConfig, id: Uuid) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.auth.pairing_status(&id).await {
+            Some(status) => Ok(Box::new(warp::reply::json(&status))),
+            None => Ok(Box::new(warp::reply::with_status("Unknown or expired pairing", StatusCode::NOT_FOUND)))
+        }
+    }
+
+    async fn confirm_pairing(config: DaemonConfig, code: String) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+        match config.auth.confirm_pairing(code.as_str()).await {
+            Some(client_name) => Ok(Box::new(client_name)),
+            None => Ok(Box::new(warp::reply::with_status("Unknown or expired pairing code".to_string(), StatusCode::NOT_FOUND)))
+        }
+    }
+
+    /// Waits for `Authenticate` (protocol v1) or `Hello` (protocol v2), returns the protocol the
+    /// response has to be written in and whether the client may continue
+    async fn websocket_handshake(ws_rx: &mut futures_util::stream::SplitStream<warp::ws::WebSocket>,
+                                 config: &DaemonConfig, version: String) -> (u8, bool, Envelope) {
+        let unauthorized = |action: &str| Envelope::event(WebSocketMessage::Error(ErrorEnvelope::new(
+            ErrorCode::Unauthorized, "Unauthorized".to_string(), Some(action.to_string()))));
+        let message = match tokio::time::timeout(Duration::from_secs(10), ws_rx.next()).await {
+            Ok(Some(Ok(message))) if message.is_text() => message,
+            _ => return (1, false, unauthorized("Authenticate"))
+        };
+        let envelope = match serde_json::from_str::<Envelope>(message.to_str().unwrap_or_default()) {
+            Ok(envelope) => envelope,
+            Err(_) => return (1, false, unauthorized("Authenticate"))
+        };
+        let request_id = envelope.request_id;
+        let (protocol, authenticated, message) = match envelope.message {
+            WebSocketMessage::Authenticate(token) => {
+                let authenticated = config.auth.is_valid(config, token.as_str()).await;
+                let welcome = Hello {
+                    protocol: 1,
+                    token,
+                    capabilities: Vec::new(),
+                }.welcome(version);
+                (1, authenticated, WebSocketMessage::Welcome(welcome))
+            }
+            WebSocketMessage::Hello(hello) if hello.protocol < 2 => {
+                return (PROTOCOL_VERSION, false, Envelope {
+                    request_id,
+                    message: WebSocketMessage::Error(ErrorEnvelope::new(ErrorCode::UnsupportedProtocol,
+                        format!("Protocol {} has to use Authenticate, Hello requires protocol 2 or newer", hello.protocol),
+                        Some("Hello".to_string()))),
+                });
+            }
+            WebSocketMessage::Hello(hello) => {
+                let authenticated = config.auth.is_valid(config, hello.token.as_str()).await;
+                let welcome = hello.welcome(version);
+                (welcome.protocol, authenticated, WebSocketMessage::Welcome(welcome))
+            }
+            _ => return (1, false, unauthorized("Authenticate"))
+        };
+        if !authenticated {
+            let action = if protocol >= 2 { "Hello" } else { "Authenticate" };
+            let mut response = unauthorized(action);
+            response.request_id = request_id;
+            return (protocol, false, response);
+        }
+        (protocol, true, Envelope {
+            request_id,
+            message,
+        })
+    }
+
     async fn websocket_connected(websocket: warp::ws::WebSocket,
-                                 tx: tokio::sync::broadcast::Sender<warp::ws::Message>,
-                                 inbound_tx: tokio::sync::mpsc::Sender<warp::ws::Message>,
-                                 config: DaemonConfig) {
+                                 sessions: Sessions,
+                                 inbound_tx: tokio::sync::mpsc::Sender<(Uuid, warp::ws::Message)>,
+                                 config: DaemonConfig,
+                                 version: String) {
         info!("WebSocket connection upgrade (connected)!");
         let (mut ws_tx, mut ws_rx) = websocket.split();
 
-        let sender_task_tx = tx.clone();
+        let (protocol, authenticated, response) = WebServer::websocket_handshake(&mut ws_rx, &config, version).await;
+        ws_tx.send(warp::ws::Message::text(response.to_text(protocol))).await.ok();
+        if !authenticated {
+            warn!("WebSocket client failed to authenticate");
+            ws_tx.send(warp::ws::Message::close()).await.ok();
+            return;
+        }
+        debug!("WebSocket client speaks protocol {}", protocol);
+
+        let topics = if protocol >= 2 { Vec::new() } else { Topic::v1_defaults() };
+        let (session, mut outbound) = sessions.register(topics);
         let handle = tokio::spawn(async move {
-            while let Ok(message) = sender_task_tx.subscribe().recv().await {
-                if message.is_text() {
-                    trace!("Sending message: {}", message.to_str().unwrap());
-                }
+            while let Some(envelope) = outbound.recv().await {
+                let text = envelope.to_text(protocol);
+                trace!(target: WEBSOCKET_SEND_TARGET, "Sending message: {}", text);
                 ws_tx
-                    .send(message)
+                    .send(warp::ws::Message::text(text))
                     .unwrap_or_else(|e| {
-                        error!("Error when sending to WS client: {}", e)
+                        error!(target: WEBSOCKET_SEND_TARGET, "Error when sending to WS client: {}", e)
                     }).await;
             }
         });
 
-        tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_millis(250)).await;
-            WebSocketHandler::send_static(tx, WebSocketMessage::Connected(config.get_configs().await));
-        });
+        sessions.send(&session, Envelope::event(WebSocketMessage::Connected(config.get_configs().await)));
 
         while let Some(result) = ws_rx.next().await {
             match result {
@@ -156,7 +619,7 @@ impl WebServer {
                         info!("WebSocket closed gracefully");
                         breakout = true;
                     }
-                    inbound_tx.send(msg).await.ok();
+                    inbound_tx.send((session, msg)).await.ok();
                     if breakout {
                         break;
                     }
@@ -168,6 +631,30 @@ impl WebServer {
             }
         }
         debug!("WebSocket connection closed!");
+        sessions.unregister(&session);
         handle.abort();
     }
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize)]
+struct CleanupQuery {
+    policy: CleanupPolicy,
+}
+
+#[derive(Deserialize)]
+struct BackupQuery {
+    #[serde(default)]
+    archive: bool,
+}
+
+#[derive(Deserialize)]
+struct QueueQuery {
+    /// Comma separated installation ids
+    installations: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReindexQuery {
+    #[serde(default)]
+    aggressive: bool,
+}
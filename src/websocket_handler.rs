@@ -3,18 +3,54 @@ use warp::ws::Message;
 use log::{info, error};
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use std::sync::Arc;
 use crate::config::DaemonConfig;
 use uuid::Uuid;
+use crate::map_query::{MapQueryRequest, MapQueryResult};
+use crate::duplicates::{CleanupRequest, CleanupResult, DuplicateReport};
+use crate::sync::{MirrorConfig, SyncRequest, SyncResult};
+use crate::routing::RoutingRules;
+use crate::quota::QuotaConfig;
+use crate::sessions::{Sessions, Topic};
+use crate::log_buffer::LogRecord;
 
+/// Newest protocol version spoken on `/pipe`
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Features of protocol v2 a client can rely on when the daemon announces them in `Welcome`
+const CAPABILITIES: [&str; 8] = ["requestIds", "typedResults", "topics", "logs", "mapQuery", "duplicates", "sync", "routing"];
+
+/// Cloned by the supervisor to restart the handler, all clones share the receiver
+#[derive(Clone)]
 pub struct WebSocketHandler {
-    rx: tokio::sync::mpsc::Receiver<Message>,
-    tx: tokio::sync::broadcast::Sender<Message>,
+    rx: Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<(Uuid, Message)>>>,
+    sessions: Sessions,
     config: DaemonConfig,
 }
 
+/// Frame of every message on `/pipe`.
+///
+/// Protocol v1 clients send `Authenticate` first and exchange bare `{"type", "data"}` messages.
+/// Protocol v2 clients send `Hello` first, may add a `requestId` to every request and get it echoed
+/// on the response. Failures are reported as `Error`, v1 clients get them as `ResultResponse` instead.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub message: WebSocketMessage,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(tag = "type", content = "data")]
 pub enum WebSocketMessage {
+    Authenticate(String),
+    Hello(Hello),
+    Welcome(Welcome),
+    Error(ErrorEnvelope),
+    OneClickRegistered(String),
+    MapInstalled(MapInstallEvent),
+    MapInstallFailed(MapInstallFailure),
     Connected(Vec<ConfigData>),
     UpdateConfig(Vec<ConfigData>),
     SetupOneClick(),
@@ -22,6 +58,75 @@ pub enum WebSocketMessage {
     InstallMaps(InstallData),
     InstallPcMods(InstallData),
     InstallQuestMods(InstallData),
+    QueryMaps(MapQueryRequest),
+    MapQueryResult(MapQueryResult),
+    FindDuplicates(Uuid),
+    DuplicateReport(DuplicateReport),
+    CleanupDuplicates(CleanupRequest),
+    DuplicatesCleaned(CleanupResult),
+    SyncInstallations(SyncRequest),
+    SyncResult(SyncResult),
+    Subscribe(Vec<Topic>),
+    Unsubscribe(Vec<Topic>),
+    Subscribed(Vec<Topic>),
+    IndexChanged(Uuid),
+    Log(LogRecord),
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hello {
+    pub protocol: u8,
+    pub token: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Welcome {
+    /// Version both sides speak, the lower one of client and daemon
+    pub protocol: u8,
+    pub daemon_version: String,
+    /// Capabilities of the daemon which the client also announced
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum ErrorCode {
+    Unauthorized,
+    UnsupportedProtocol,
+    InvalidMessage,
+    UnknownInstallation,
+    NotImplemented,
+    OneClickFailed,
+    SyncFailed,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorEnvelope {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Type of the request which failed, if the error belongs to one
+    pub action: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapInstallEvent {
+    pub installation: Uuid,
+    pub map: String,
+    pub hash: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapInstallFailure {
+    /// None if the map failed before reaching an installation
+    pub installation: Option<Uuid>,
+    pub map: String,
+    pub error: String,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -30,6 +135,14 @@ pub struct ConfigData {
     pub rest_token: String,
     pub install_type: InstallType,
     pub install_location: String,
+    #[serde(default)]
+    pub map_folder: Option<String>,
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+    #[serde(default)]
+    pub routing: RoutingRules,
+    #[serde(default)]
+    pub quota: QuotaConfig,
 }
 
 #[derive(Clone, Deserialize, Serialize, PartialEq)]
@@ -53,6 +166,68 @@ pub enum ResultMessageData {
     MapInstallSuccess(Uuid, String, String)
 }
 
+impl Hello {
+    pub fn welcome(&self, daemon_version: String) -> Welcome {
+        Welcome {
+            protocol: self.protocol.min(PROTOCOL_VERSION),
+            daemon_version,
+            capabilities: CAPABILITIES.iter()
+                .filter(|capability| self.capabilities.iter().any(|requested| requested.eq(*capability)))
+                .map(|capability| capability.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl ErrorEnvelope {
+    pub fn new(code: ErrorCode, message: String, action: Option<String>) -> ErrorEnvelope {
+        ErrorEnvelope {
+            code,
+            message,
+            action,
+        }
+    }
+}
+
+impl Envelope {
+    pub fn event(message: WebSocketMessage) -> Envelope {
+        Envelope {
+            request_id: None,
+            message,
+        }
+    }
+
+    /// Serializes the message for a client of the given protocol version
+    pub fn to_text(&self, protocol: u8) -> String {
+        if protocol >= 2 {
+            serde_json::to_string(self).unwrap()
+        } else {
+            serde_json::to_string(&self.message.clone().into_v1()).unwrap()
+        }
+    }
+}
+
+impl WebSocketMessage {
+    /// Maps the typed results of protocol v2 to the `ResultResponse` shapes v1 clients understand
+    pub fn into_v1(self) -> WebSocketMessage {
+        let (action, success, data) = match self {
+            WebSocketMessage::Welcome(_) => ("Authenticate".to_string(), true, ResultMessageData::Simple("OK".to_string())),
+            WebSocketMessage::Error(error) => (error.action.unwrap_or_default(), false, ResultMessageData::Simple(error.message)),
+            WebSocketMessage::OneClickRegistered(message) => ("SetupOneClick".to_string(), true, ResultMessageData::Simple(message)),
+            WebSocketMessage::MapInstalled(event) =>
+                ("InstallMaps".to_string(), true, ResultMessageData::MapInstallSuccess(event.installation, event.map, event.hash)),
+            WebSocketMessage::MapInstallFailed(failure) =>
+                ("InstallMaps".to_string(), false, ResultMessageData::MapInstallError(failure.installation, failure.map, failure.error)),
+            message => return message
+        };
+        WebSocketMessage::ResultResponse(ResultMsg {
+            action,
+            success,
+            data,
+        })
+    }
+}
+
 impl ToString for InstallType {
     fn to_string(&self) -> String {
         match self {
@@ -86,47 +261,88 @@ pub struct ClientIdentity {
 }
 
 impl WebSocketHandler {
-    pub fn new(tx: tokio::sync::broadcast::Sender<Message>,
-               rx: tokio::sync::mpsc::Receiver<Message>,
+    pub fn new(sessions: Sessions,
+               rx: tokio::sync::mpsc::Receiver<(Uuid, Message)>,
                config: DaemonConfig) -> WebSocketHandler {
         WebSocketHandler {
-            rx,
-            tx,
+            rx: Arc::new(tokio::sync::Mutex::new(rx)),
+            sessions,
             config,
         }
     }
 
-    pub fn get_sender(&self) -> tokio::sync::broadcast::Sender<Message> {
-        self.tx.clone()
+    pub fn get_sessions(&self) -> Sessions {
+        self.sessions.clone()
     }
 
-    pub(crate) fn start(mut self) -> JoinHandle<()> {
+    pub(crate) fn start(self) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let tx = self.tx.clone();
-            while let Some(message) = self.rx.recv().await {
-                if message.is_ping() {
-                    tx.send(Message::pong(message.into_bytes())).ok();
-                } else if message.is_text() {
-                    if let Ok(text) = message.to_str() {
-                        let msg: WebSocketMessage = match serde_json::from_str(text) {
-                            Ok(parsed) => parsed,
-                            Err(error) => {
-                                error!("An error occurred when trying to parse incoming WebSocket message: {}", error);
-                                continue;
-                            }
-                        };
-                        if let Some(msg) = self.handle_web_socket_message(msg).await {
-                            tx.send(Message::text(serde_json::to_string(&msg).unwrap())).ok();
+            // one task for events and requests, so a restart doesn't leave forwarders behind
+            tokio::select! {
+                _val = self.forward_log_events() => {}
+                _val = self.forward_index_events() => {}
+                _val = self.handle_requests() => {}
+            }
+        })
+    }
+
+    async fn forward_log_events(&self) {
+        let mut log_events = crate::log_buffer::subscribe_log_events();
+        loop {
+            match log_events.recv().await {
+                Ok(record) => self.sessions.publish(Topic::Logs, WebSocketMessage::Log(record)),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break
+            }
+        }
+    }
+
+    async fn forward_index_events(&self) {
+        let mut index_events = crate::config::subscribe_index_events();
+        loop {
+            match index_events.recv().await {
+                Ok(installation) => self.sessions.publish(Topic::Index, WebSocketMessage::IndexChanged(installation)),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break
+            }
+        }
+    }
+
+    async fn handle_requests(&self) {
+        let mut rx = self.rx.lock().await;
+        // pings are answered by the connection itself
+        while let Some((session, message)) = rx.recv().await {
+            if message.is_text() {
+                if let Ok(text) = message.to_str() {
+                    let envelope: Envelope = match serde_json::from_str(text) {
+                        Ok(parsed) => parsed,
+                        Err(error) => {
+                            error!("An error occurred when trying to parse incoming WebSocket message: {}", error);
+                            self.sessions.send(&session, Envelope::event(WebSocketMessage::Error(ErrorEnvelope::new(
+                                ErrorCode::InvalidMessage, error.to_string(), None))));
+                            continue;
                         }
+                    };
+                    if let Some(msg) = self.handle_web_socket_message(&session, envelope.message).await {
+                        self.sessions.send(&session, Envelope {
+                            request_id: envelope.request_id,
+                            message: msg,
+                        });
                     }
                 }
             }
-        })
+        }
     }
 
-    pub async fn handle_web_socket_message(&self, message: WebSocketMessage) -> Option<WebSocketMessage> {
+    pub async fn handle_web_socket_message(&self, session: &Uuid, message: WebSocketMessage) -> Option<WebSocketMessage> {
         let action = message.to_string();
         match message {
+            WebSocketMessage::Subscribe(topics) => {
+                Some(WebSocketMessage::Subscribed(self.sessions.subscribe(session, topics.as_slice(), true)))
+            }
+            WebSocketMessage::Unsubscribe(topics) => {
+                Some(WebSocketMessage::Subscribed(self.sessions.subscribe(session, topics.as_slice(), false)))
+            }
             WebSocketMessage::UpdateConfig(configs) => {
                 info!("Updating configs...");
                 let updated = self.config.update_configs(configs).await;
@@ -134,61 +350,90 @@ impl WebSocketHandler {
             }
             WebSocketMessage::SetupOneClick() => {
                 info!("Setting up one-click...");
-                let result = crate::one_click::register_one_click();
-                let success = result.is_ok();
-                let msg = match result {
-                    Ok(msg) => msg,
-                    Err(msg) => msg
-                };
-                Some(WebSocketMessage::ResultResponse(ResultMsg {
-                    action,
-                    success,
-                    data: ResultMessageData::Simple(msg),
-                }))
+                Some(match crate::one_click::register_one_click() {
+                    Ok(msg) => WebSocketMessage::OneClickRegistered(msg),
+                    Err(msg) => WebSocketMessage::Error(ErrorEnvelope::new(ErrorCode::OneClickFailed, msg, Some(action)))
+                })
+            }
+            WebSocketMessage::InstallMaps(_) | WebSocketMessage::InstallPcMods(_) | WebSocketMessage::InstallQuestMods(_) => {
+                Some(WebSocketMessage::Error(ErrorEnvelope::new(ErrorCode::NotImplemented, "Not implemented".to_string(), Some(action))))
+            }
+            WebSocketMessage::QueryMaps(request) => {
+                match self.config.get_local_data(&request.installation).await {
+                    Some(local_data) => {
+                        let index = local_data.map_index.lock().await;
+                        Some(WebSocketMessage::MapQueryResult(request.query.execute(request.installation, &index)))
+                    }
+                    None => Some(unknown_installation(action))
+                }
             }
-            WebSocketMessage::InstallMaps(_maps) => {
-                Some(WebSocketMessage::ResultResponse(ResultMsg {
-                    action,
-                    success: false,
-                    data: ResultMessageData::Simple("Not implemented".to_string()),
-                }))
+            WebSocketMessage::FindDuplicates(installation) => {
+                match self.config.get_local_data(&installation).await {
+                    Some(local_data) => {
+                        let index = local_data.map_index.lock().await;
+                        Some(WebSocketMessage::DuplicateReport(crate::duplicates::find_duplicates(installation, &index)))
+                    }
+                    None => Some(unknown_installation(action))
+                }
             }
-            WebSocketMessage::InstallPcMods(_mods) => {
-                Some(WebSocketMessage::ResultResponse(ResultMsg {
-                    action,
-                    success: false,
-                    data: ResultMessageData::Simple("Not implemented".to_string()),
-                }))
+            WebSocketMessage::CleanupDuplicates(request) => {
+                match self.config.get_local_data(&request.installation).await {
+                    Some(local_data) => {
+                        info!("Cleaning up duplicates...");
+                        Some(WebSocketMessage::DuplicatesCleaned(crate::duplicates::cleanup_duplicates(&local_data, request.policy).await))
+                    }
+                    None => Some(unknown_installation(action))
+                }
             }
-            WebSocketMessage::InstallQuestMods(_mods) => {
-                Some(WebSocketMessage::ResultResponse(ResultMsg {
-                    action,
-                    success: false,
-                    data: ResultMessageData::Simple("Not implemented".to_string()),
-                }))
+            WebSocketMessage::SyncInstallations(request) => {
+                match crate::sync::sync_installations(&self.config, &request).await {
+                    Ok(result) => Some(WebSocketMessage::SyncResult(result)),
+                    Err(err) => Some(WebSocketMessage::Error(ErrorEnvelope::new(ErrorCode::SyncFailed, err.to_string(), Some(action))))
+                }
             }
+            WebSocketMessage::Authenticate(_) | WebSocketMessage::Hello(_) => None,
             _ => {
                 error!("Received client message from server");
                 None
             }
         }
     }
+}
 
-    pub fn send_static(tx: tokio::sync::broadcast::Sender<Message>, message: WebSocketMessage) {
-        tx.send(Message::text(serde_json::to_string(&message).unwrap())).ok();
-    }
+fn unknown_installation(action: String) -> WebSocketMessage {
+    WebSocketMessage::Error(ErrorEnvelope::new(ErrorCode::UnknownInstallation, "Unknown installation".to_string(), Some(action)))
 }
 
 impl ToString for WebSocketMessage {
     fn to_string(&self) -> String {
         match self {
+            WebSocketMessage::Authenticate(_) => "Authenticate",
+            WebSocketMessage::Hello(_) => "Hello",
+            WebSocketMessage::Welcome(_) => "Welcome",
+            WebSocketMessage::Error(_) => "Error",
+            WebSocketMessage::OneClickRegistered(_) => "OneClickRegistered",
+            WebSocketMessage::MapInstalled(_) => "MapInstalled",
+            WebSocketMessage::MapInstallFailed(_) => "MapInstallFailed",
             WebSocketMessage::Connected(_) => "Connected",
             WebSocketMessage::UpdateConfig(_) => "UpdateConfig",
             WebSocketMessage::SetupOneClick() => "SetupOneClick",
             WebSocketMessage::ResultResponse(_) => "ResultResponse",
             WebSocketMessage::InstallMaps(_) => "InstallMaps",
             WebSocketMessage::InstallPcMods(_) => "InstallPcMods",
-            WebSocketMessage::InstallQuestMods(_) => "InstallQuestMods"
+            WebSocketMessage::InstallQuestMods(_) => "InstallQuestMods",
+            WebSocketMessage::QueryMaps(_) => "QueryMaps",
+            WebSocketMessage::MapQueryResult(_) => "MapQueryResult",
+            WebSocketMessage::FindDuplicates(_) => "FindDuplicates",
+            WebSocketMessage::DuplicateReport(_) => "DuplicateReport",
+            WebSocketMessage::CleanupDuplicates(_) => "CleanupDuplicates",
+            WebSocketMessage::DuplicatesCleaned(_) => "DuplicatesCleaned",
+            WebSocketMessage::SyncInstallations(_) => "SyncInstallations",
+            WebSocketMessage::SyncResult(_) => "SyncResult",
+            WebSocketMessage::Subscribe(_) => "Subscribe",
+            WebSocketMessage::Unsubscribe(_) => "Unsubscribe",
+            WebSocketMessage::Subscribed(_) => "Subscribed",
+            WebSocketMessage::IndexChanged(_) => "IndexChanged",
+            WebSocketMessage::Log(_) => "Log"
         }.to_string()
     }
 }
\ No newline at end of file
@@ -0,0 +1,10 @@
+use std::path::PathBuf;
+
+/// Map directory below fixtures/maps
+pub fn fixture(name: &str) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("fixtures");
+    path.push("maps");
+    path.push(name);
+    path
+}
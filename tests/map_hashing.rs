@@ -0,0 +1,95 @@
+#[path = "../src/map_index.rs"]
+#[allow(dead_code)]
+mod map_index;
+mod common;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use common::fixture;
+use map_index::IndexError;
+
+/// Zips the files of a map directory into the temp directory, like a BeatSaver download
+fn zip_fixture(name: &str, skip: &[&str]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("aiosaber-{}-{}-{}.zip", name, skip.len(), std::process::id()));
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+    for entry in std::fs::read_dir(fixture(name)).unwrap() {
+        let file_path = entry.unwrap().path();
+        let file_name = file_path.file_name().unwrap().to_str().unwrap().to_string();
+        if skip.contains(&file_name.as_str()) {
+            continue;
+        }
+        zip.start_file(file_name, zip::write::FileOptions::default()).unwrap();
+        zip.write_all(std::fs::read(&file_path).unwrap().as_ref()).unwrap();
+    }
+    zip.finish().unwrap();
+    path
+}
+
+fn hash_zip(path: &Path) -> Result<String, IndexError> {
+    let hash = map_index::generate_hash_from_zip(path);
+    std::fs::remove_file(path).ok();
+    hash
+}
+
+#[test]
+fn hashes_v2_map() {
+    let hash = map_index::generate_hash(fixture("v2")).unwrap();
+    assert_eq!(hash, "62beebcc774c474a51ddbbe929c4d8ec4d1a2086");
+}
+
+#[test]
+fn hashes_v3_map_with_capitalized_info_dat() {
+    let hash = map_index::generate_hash(fixture("v3")).unwrap();
+    assert_eq!(hash, "dd9b3e78d73d64c5fb369773c8c843a5de1d9c1d");
+}
+
+#[test]
+fn hashes_v4_map() {
+    let hash = map_index::generate_hash(fixture("v4")).unwrap();
+    assert_eq!(hash, "8b3f643635da9eba6acd823b99b80671d5c62164");
+}
+
+#[test]
+fn rejects_map_with_missing_difficulty() {
+    match map_index::generate_hash(fixture("missing-difficulty")) {
+        Err(IndexError::InvalidDifficulty(_, path)) => assert!(path.ends_with("ExpertPlusStandard.dat")),
+        _ => panic!("expected InvalidDifficulty"),
+    }
+}
+
+#[test]
+fn rejects_directory_without_info_dat() {
+    match map_index::generate_hash(fixture("does-not-exist")) {
+        Err(IndexError::NotAMap(_, _)) => {}
+        _ => panic!("expected NotAMap"),
+    }
+}
+
+#[test]
+fn sums_sizes_of_hashed_files() {
+    assert_eq!(map_index::hashed_size(fixture("v2").as_path()).unwrap(), 999 + 131 + 201);
+}
+
+#[test]
+fn hashes_zipped_maps_like_extracted_ones() {
+    for name in ["v2", "v3", "v4"].iter() {
+        let hash = hash_zip(zip_fixture(name, &[]).as_path()).unwrap();
+        assert_eq!(hash, map_index::generate_hash(fixture(name)).unwrap(), "{}", name);
+    }
+}
+
+#[test]
+fn rejects_zip_without_info_dat() {
+    match hash_zip(zip_fixture("v2", &["info.dat"]).as_path()) {
+        Err(IndexError::NotAMap(_, _)) => {}
+        _ => panic!("expected NotAMap"),
+    }
+}
+
+#[test]
+fn rejects_zip_with_missing_difficulty() {
+    match hash_zip(zip_fixture("v2", &["ExpertStandard.dat"]).as_path()) {
+        Err(IndexError::InvalidDifficulty(_, _)) => {}
+        _ => panic!("expected InvalidDifficulty"),
+    }
+}
@@ -0,0 +1,44 @@
+#[path = "../src/map_index.rs"]
+#[allow(dead_code)]
+mod map_index;
+mod common;
+
+use common::fixture;
+
+#[test]
+fn reads_v2_map_info() {
+    let info = map_index::read_map_info(fixture("v2").as_path()).unwrap();
+    assert_eq!(info.song_name, "Fixture Song");
+    assert_eq!(info.song_sub_name, "v2");
+    assert_eq!(info.song_author_name, "Fixture Artist");
+    assert_eq!(info.level_author_name, "Fixture Mapper");
+    assert_eq!(info.bpm, 128f32);
+    assert_eq!(info.duration, None);
+    assert_eq!(info.cover_image_filename.as_deref(), Some("cover.jpg"));
+    assert_eq!(info.environment_name.as_deref(), Some("DefaultEnvironment"));
+    let difficulties = info.difficulties.iter()
+        .map(|difficulty| (difficulty.characteristic.as_str(), difficulty.difficulty.as_str()))
+        .collect::<Vec<(&str, &str)>>();
+    assert_eq!(difficulties, vec![("Standard", "Expert"), ("Standard", "ExpertPlus")]);
+}
+
+#[test]
+fn reads_characteristics_of_v3_map_info() {
+    let info = map_index::read_map_info(fixture("v3").as_path()).unwrap();
+    let characteristics = info.difficulties.iter()
+        .map(|difficulty| difficulty.characteristic.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(characteristics, vec!["Standard", "OneSaber"]);
+}
+
+#[test]
+fn reads_v4_map_info() {
+    let info = map_index::read_map_info(fixture("v4").as_path()).unwrap();
+    assert_eq!(info.song_name, "Fixture Song");
+    assert_eq!(info.song_sub_name, "v4");
+    assert_eq!(info.level_author_name, "Fixture Mapper");
+    assert_eq!(info.bpm, 160f32);
+    assert_eq!(info.duration, Some(180f32));
+    assert_eq!(info.environment_name.as_deref(), Some("WeaveEnvironment"));
+    assert_eq!(info.difficulties.len(), 2);
+}